@@ -0,0 +1,304 @@
+//! Throughput, peak memory and allocations of the engine over generated workloads.
+//!
+//! `cargo bench --bench throughput -- 1m 10m 100m` runs the given sizes, 1m by default.
+//! Every result is appended as a json line to `target/bench-results.jsonl`
+//! together with the commit it was measured on.
+
+use jellyfish_engine::config::InvalidRows;
+use jellyfish_engine::input::{parse_transactions, TransactionReader};
+use jellyfish_engine::transaction::Transaction;
+use jellyfish_engine::workload::{Generator, WorkloadConfig};
+use jellyfish_engine::ClientTransactionHandler;
+use serde::{Deserialize, Serialize};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The same seed for every run, so results of different commits are comparable.
+const SEED: u64 = 42;
+
+/// Rows `bench_add_transaction` parses ahead of each measured loop, so large
+/// workloads don't have to fit in memory.
+const CHUNK_ROWS: usize = 65_536;
+
+/// Counts every allocation of the process.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(new_size as u64, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Allocations {
+    count: u64,
+    bytes: u64,
+}
+
+impl Allocations {
+    fn now() -> Self {
+        Self {
+            count: ALLOCATIONS.load(Ordering::Relaxed),
+            bytes: ALLOCATED_BYTES.load(Ordering::Relaxed),
+        }
+    }
+
+    fn since(self, start: Self) -> Self {
+        Self {
+            count: self.count - start.count,
+            bytes: self.bytes - start.bytes,
+        }
+    }
+
+    fn plus(self, other: Self) -> Self {
+        Self {
+            count: self.count + other.count,
+            bytes: self.bytes + other.bytes,
+        }
+    }
+}
+
+/// A line of the results file.
+#[derive(Debug, Serialize, Deserialize)]
+struct BenchResult {
+    bench: String,
+    rows: u64,
+    commit: String,
+    unix_time: u64,
+    seconds: f64,
+    rows_per_second: f64,
+    /// Only known on Linux.
+    peak_rss_bytes: Option<u64>,
+    allocations_per_row: f64,
+    allocated_bytes_per_row: f64,
+}
+
+impl BenchResult {
+    fn new(bench: &str, rows: u64, elapsed: Duration, allocations: Allocations) -> Self {
+        let per_row = |value: u64| value as f64 / rows.max(1) as f64;
+        Self {
+            bench: bench.to_string(),
+            rows,
+            commit: current_commit(),
+            unix_time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs()),
+            seconds: elapsed.as_secs_f64(),
+            rows_per_second: rows as f64 / elapsed.as_secs_f64(),
+            peak_rss_bytes: peak_rss(),
+            allocations_per_row: per_row(allocations.count),
+            allocated_bytes_per_row: per_row(allocations.bytes),
+        }
+    }
+}
+
+/// The peak resident set size since the last `reset_peak_rss`.
+fn peak_rss() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+fn reset_peak_rss() {
+    // not every kernel allows this, the peak then covers the earlier benches too
+    let _ = fs::write("/proc/self/clear_refs", "5");
+}
+
+fn current_commit() -> String {
+    let output = Command::new("git")
+        .args(["describe", "--always", "--dirty"])
+        .output();
+    match output {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        }
+        _ => "unknown".to_string(),
+    }
+}
+
+/// Parses sizes like `1m`, `250k` or `5000`.
+fn parse_rows(arg: &str) -> Option<u64> {
+    let (digits, factor) = match arg.to_ascii_lowercase() {
+        arg if arg.ends_with('m') => (arg.trim_end_matches('m').to_string(), 1_000_000),
+        arg if arg.ends_with('k') => (arg.trim_end_matches('k').to_string(), 1_000),
+        arg => (arg, 1),
+    };
+    digits.parse::<u64>().ok().map(|rows| rows * factor)
+}
+
+/// The workload of `rows` rows, generated on first use and kept for later runs.
+fn dataset(dir: &Path, rows: u64) -> io::Result<PathBuf> {
+    let path = dir.join(format!("workload-{}-{}.csv", rows, SEED));
+    if path.exists() {
+        return Ok(path);
+    }
+    eprintln!("generating {}", path.display());
+    fs::create_dir_all(dir)?;
+    let partial = path.with_extension("partial");
+    let mut generator = Generator::new(WorkloadConfig {
+        clients: u16::MAX,
+        transactions: rows,
+        seed: SEED,
+        ..WorkloadConfig::default()
+    });
+    generator.write_csv(BufWriter::new(File::create(&partial)?))?;
+    fs::rename(partial, &path)?;
+    Ok(path)
+}
+
+/// The whole ingestion, from the file to the updated accounts.
+fn bench_parse_transactions(path: &Path, rows: u64) -> io::Result<BenchResult> {
+    reset_peak_rss();
+    let mut handler = ClientTransactionHandler::new();
+    let file = File::open(path)?;
+    let (start, allocations) = (Instant::now(), Allocations::now());
+    parse_transactions(file, "bench", InvalidRows::Fail, &mut handler)?;
+    let result = BenchResult::new(
+        "parse_transactions",
+        rows,
+        start.elapsed(),
+        Allocations::now().since(allocations),
+    );
+    drop(handler);
+    Ok(result)
+}
+
+/// The csv reader on its own, from the file to typed rows.
+fn bench_read_transactions(path: &Path, rows: u64) -> io::Result<BenchResult> {
+    reset_peak_rss();
+    let mut reader = TransactionReader::new(BufReader::new(File::open(path)?))?;
+    let (start, allocations) = (Instant::now(), Allocations::now());
+    while let Some(transaction) = reader.next_transaction()? {
+        std::hint::black_box(transaction);
+    }
+    Ok(BenchResult::new(
+        "read_transactions",
+        rows,
+        start.elapsed(),
+        Allocations::now().since(allocations),
+    ))
+}
+
+/// The same rows through serde with trimming by the csv reader, to compare with.
+fn bench_deserialize_transactions(path: &Path, rows: u64) -> io::Result<BenchResult> {
+    reset_peak_rss();
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(BufReader::new(File::open(path)?));
+    let (start, allocations) = (Instant::now(), Allocations::now());
+    for transaction in reader.deserialize::<Transaction>() {
+        std::hint::black_box(transaction?);
+    }
+    Ok(BenchResult::new(
+        "deserialize_transactions",
+        rows,
+        start.elapsed(),
+        Allocations::now().since(allocations),
+    ))
+}
+
+/// The handler on its own, the rows are parsed in chunks of `CHUNK_ROWS` between
+/// the measured loops.
+///
+/// The peak resident set size includes one chunk of parsed rows.
+fn bench_add_transaction(path: &Path, rows: u64) -> io::Result<BenchResult> {
+    reset_peak_rss();
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(BufReader::new(File::open(path)?));
+    let mut transactions = reader.deserialize::<Transaction>();
+    let mut chunk = Vec::with_capacity(CHUNK_ROWS);
+    let mut handler = ClientTransactionHandler::new();
+    let (mut elapsed, mut allocations) = (Duration::ZERO, Allocations::default());
+    loop {
+        for transaction in transactions.by_ref().take(CHUNK_ROWS) {
+            chunk.push(transaction?);
+        }
+        if chunk.is_empty() {
+            break;
+        }
+        let (start, before) = (Instant::now(), Allocations::now());
+        for transaction in chunk.drain(..) {
+            let _ = handler.add_transaction(transaction);
+        }
+        elapsed += start.elapsed();
+        allocations = allocations.plus(Allocations::now().since(before));
+    }
+    let result = BenchResult::new("add_transaction", rows, elapsed, allocations);
+    drop(handler);
+    Ok(result)
+}
+
+/// The last result of the same bench and size, to compare against.
+fn previous_result(results: &Path, bench: &BenchResult) -> Option<BenchResult> {
+    let file = File::open(results).ok()?;
+    BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str::<BenchResult>(&line).ok())
+        .filter(|previous| previous.bench == bench.bench && previous.rows == bench.rows)
+        .last()
+}
+
+fn report(results: &Path, result: &BenchResult) -> io::Result<()> {
+    let rss = result.peak_rss_bytes.map_or("-".to_string(), |bytes| {
+        format!("{} MiB", bytes / (1 << 20))
+    });
+    let change = previous_result(results, result).map_or(String::new(), |previous| {
+        let change = (result.rows_per_second / previous.rows_per_second - 1.0) * 100.0;
+        format!(" ({:+.1}% rows/s since {})", change, previous.commit)
+    });
+    println!(
+        "{:<24} {:>11} rows {:>12.0} rows/s  peak rss {:>9}  {:.2} allocations/row{}",
+        result.bench, result.rows, result.rows_per_second, rss, result.allocations_per_row, change
+    );
+
+    let mut file = OpenOptions::new().create(true).append(true).open(results)?;
+    writeln!(file, "{}", serde_json::to_string(result)?)
+}
+
+fn main() -> io::Result<()> {
+    let mut sizes: Vec<u64> = std::env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with("--"))
+        .filter_map(|arg| parse_rows(&arg))
+        .collect();
+    if sizes.is_empty() {
+        sizes.push(1_000_000);
+    }
+
+    let target = Path::new(env!("CARGO_MANIFEST_DIR")).join("target");
+    let results = target.join("bench-results.jsonl");
+    for rows in sizes {
+        let path = dataset(&target.join("bench-data"), rows)?;
+        report(&results, &bench_read_transactions(&path, rows)?)?;
+        report(&results, &bench_deserialize_transactions(&path, rows)?)?;
+        report(&results, &bench_parse_transactions(&path, rows)?)?;
+        report(&results, &bench_add_transaction(&path, rows)?)?;
+    }
+    Ok(())
+}
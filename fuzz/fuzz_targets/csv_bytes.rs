@@ -0,0 +1,16 @@
+#![no_main]
+
+use jellyfish_engine::config::InvalidRows;
+use jellyfish_engine::input::parse_transactions;
+use jellyfish_engine::ClientTransactionHandler;
+use jellyfish_engine_fuzz::assert_no_negative_balances;
+use libfuzzer_sys::fuzz_target;
+
+// Arbitrary bytes as a partner file, rows that can't be read are fine as long as nothing panics.
+fuzz_target!(|data: &[u8]| {
+    for invalid_rows in [InvalidRows::Fail, InvalidRows::Skip] {
+        let mut handler = ClientTransactionHandler::new();
+        let _ = parse_transactions(data, "fuzz", invalid_rows, &mut handler);
+        assert_no_negative_balances(&handler);
+    }
+});
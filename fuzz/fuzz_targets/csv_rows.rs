@@ -0,0 +1,115 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use jellyfish_engine::config::InvalidRows;
+use jellyfish_engine::input::parse_transactions;
+use jellyfish_engine::transaction::{Transaction, TxType};
+use jellyfish_engine::ClientTransactionHandler;
+use jellyfish_engine_fuzz::assert_no_negative_balances;
+use libfuzzer_sys::fuzz_target;
+use std::collections::HashMap;
+
+/// The types of the input format and one the engine does not know.
+const TX_TYPES: [&str; 14] = [
+    "deposit",
+    "withdrawal",
+    "dispute",
+    "resolve",
+    "chargeback",
+    "authorize",
+    "capture",
+    "void",
+    "pending_deposit",
+    "settle",
+    "return",
+    "reversal",
+    "refund",
+    "transfer",
+];
+
+/// A well formed row. Few clients and ids, so rows refer to each other.
+#[derive(Debug, Arbitrary)]
+struct Row {
+    tx_type: u8,
+    client: u8,
+    tx: u8,
+    /// In ten thousandths, so it has at most four decimal places.
+    amount: Option<u32>,
+    /// Whether the fields are padded with whitespace.
+    padded: bool,
+}
+
+impl Row {
+    fn fields(&self) -> (&'static str, u16, u32, Option<String>) {
+        let tx_type = TX_TYPES[usize::from(self.tx_type) % TX_TYPES.len()];
+        let amount = self
+            .amount
+            .map(|amount| format!("{}.{:04}", amount / 10_000, amount % 10_000));
+        (
+            tx_type,
+            u16::from(self.client % 4),
+            u32::from(self.tx % 32),
+            amount,
+        )
+    }
+
+    fn to_csv(&self) -> String {
+        let (tx_type, client, tx, amount) = self.fields();
+        let pad = if self.padded { " " } else { "" };
+        format!(
+            "{tx_type},{pad}{client},{pad}{tx},{pad}{}\n",
+            amount.unwrap_or_default()
+        )
+    }
+
+    fn to_transaction(&self) -> Transaction {
+        let (tx_type, client, tx, amount) = self.fields();
+        let amount = amount.map(|amount| amount.parse().unwrap());
+        Transaction::new(tx_type, client, tx, amount)
+    }
+}
+
+// Random csv goes through the parser and the same rows straight into the handler,
+// both have to end up with the same, balanced accounts.
+fuzz_target!(|rows: Vec<Row>| {
+    let csv: String = std::iter::once("type,client,tx,amount\n".to_string())
+        .chain(rows.iter().map(Row::to_csv))
+        .collect();
+    let mut parsed = ClientTransactionHandler::new();
+    parse_transactions(csv.as_bytes(), "fuzz", InvalidRows::Fail, &mut parsed).unwrap();
+
+    // funds held for a dispute are always there to resolve or charge it back
+    let mut direct = ClientTransactionHandler::new();
+    let mut disputes = HashMap::new();
+    for row in &rows {
+        let t = row.to_transaction();
+        let (tx, client) = (t.id(), t.client_id());
+        let tx_type = t.tx_type().ok();
+        let accepted = direct.add_transaction(t).is_ok();
+        match tx_type {
+            Some(TxType::Dispute) if accepted => {
+                disputes.insert(tx, client);
+            }
+            Some(TxType::Resolve | TxType::Chargeback) if disputes.get(&tx) == Some(&client) => {
+                assert!(
+                    accepted,
+                    "{:?} of disputed transaction {} failed",
+                    tx_type, tx
+                );
+                disputes.remove(&tx);
+            }
+            _ => {}
+        }
+    }
+
+    for handler in [&parsed, &direct] {
+        assert_no_negative_balances(handler);
+        handler.check_trial_balance().unwrap();
+    }
+    assert_eq!(parsed.clients().len(), direct.clients().len());
+    for (id, client) in parsed.clients() {
+        let other = &direct.clients()[id];
+        assert_eq!(client.balances(), other.balances());
+        assert_eq!(client.locked(), other.locked());
+    }
+});
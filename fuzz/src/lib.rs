@@ -0,0 +1,23 @@
+//! Checks shared by the fuzz targets.
+
+use jellyfish_engine::amount::Amount;
+use jellyfish_engine::ClientTransactionHandler;
+
+/// Panics if any account went negative. Every debit checks its funds first,
+/// so this holds for any input.
+pub fn assert_no_negative_balances(handler: &ClientTransactionHandler) {
+    for client in handler.clients().values() {
+        let balances = client.balances();
+        let accounts = [
+            balances.available,
+            balances.held,
+            balances.authorized,
+            balances.pending,
+        ];
+        assert!(
+            accounts.iter().all(|balance| *balance >= Amount::ZERO),
+            "negative balance: {:?}",
+            client
+        );
+    }
+}
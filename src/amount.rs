@@ -0,0 +1,137 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Neg, Sub, SubAssign};
+
+/// Amounts have at most four decimal places, they are counted in ten-thousandths.
+const SCALE: i128 = 10_000;
+
+/// An amount of money in ten-thousandths. Unlike with `f64`, sums and differences
+/// are exact, so a balance is always exactly what its rows add up to, and funds
+/// held for a dispute can always be released again.
+///
+/// Rows carry at most `validation::MAX_AMOUNT`, so balances can't overflow.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(i128);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    /// The amount closest to `value`. Rows are validated to have at most four
+    /// decimal places, so for them it is the amount that was written.
+    pub fn from_f64(value: f64) -> Self {
+        Self((value * SCALE as f64).round() as i128)
+    }
+
+    pub fn from_ten_thousandths(value: i64) -> Self {
+        Self(i128::from(value))
+    }
+
+    /// The closest `f64`, what the amount is printed as.
+    pub fn to_f64(self) -> f64 {
+        self.0 as f64 / SCALE as f64
+    }
+
+    pub fn ten_thousandths(self) -> i128 {
+        self.0
+    }
+}
+
+impl Add for Amount {
+    type Output = Amount;
+
+    fn add(self, other: Amount) -> Amount {
+        Amount(self.0 + other.0)
+    }
+}
+
+impl Sub for Amount {
+    type Output = Amount;
+
+    fn sub(self, other: Amount) -> Amount {
+        Amount(self.0 - other.0)
+    }
+}
+
+impl Neg for Amount {
+    type Output = Amount;
+
+    fn neg(self) -> Amount {
+        Amount(-self.0)
+    }
+}
+
+impl AddAssign for Amount {
+    fn add_assign(&mut self, other: Amount) {
+        self.0 += other.0;
+    }
+}
+
+impl SubAssign for Amount {
+    fn sub_assign(&mut self, other: Amount) {
+        self.0 -= other.0;
+    }
+}
+
+impl Sum for Amount {
+    fn sum<I: Iterator<Item = Amount>>(amounts: I) -> Amount {
+        amounts.fold(Amount::ZERO, Add::add)
+    }
+}
+
+/// The exact decimal, with at least one decimal place like `f64` values are printed.
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let (units, fraction) = (self.0.abs() / SCALE, self.0.abs() % SCALE);
+        let fraction = format!("{:04}", fraction);
+        let fraction = match fraction.trim_end_matches('0') {
+            "" => "0",
+            trimmed => trimmed,
+        };
+        write!(f, "{}{}.{}", sign, units, fraction)
+    }
+}
+
+/// Outputs get the amount as a number, checkpoints keep the ten-thousandths.
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_f64(self.to_f64())
+        } else {
+            serializer.serialize_i128(self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            f64::deserialize(deserializer).map(Amount::from_f64)
+        } else {
+            i128::deserialize(deserializer).map(Amount)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Amount;
+
+    #[test]
+    fn amounts_add_up_exactly() {
+        let sum = Amount::from_f64(0.7) + Amount::from_f64(0.1);
+        assert_eq!(sum - Amount::from_f64(0.7), Amount::from_f64(0.1));
+        assert_eq!(sum.to_string(), "0.8");
+        assert_eq!(Amount::from_f64(-12.3456).to_string(), "-12.3456");
+        assert_eq!(Amount::from_f64(10.0).to_string(), "10.0");
+
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&sum, &mut cbor).unwrap();
+        assert_eq!(
+            ciborium::from_reader::<Amount, _>(cbor.as_slice()).unwrap(),
+            sum
+        );
+        assert_eq!(serde_json::to_string(&sum).unwrap(), "0.8");
+    }
+}
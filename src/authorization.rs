@@ -0,0 +1,35 @@
+use crate::amount::Amount;
+use serde::{Deserialize, Serialize};
+
+/// Funds reserved by an `authorize` transaction until they are captured,
+/// voided or the hold expires. The hold id is the id of the `authorize` transaction.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuthorizationHold {
+    client_id: u16,
+    remaining: Amount,
+    expires_at: Option<u64>,
+}
+
+impl AuthorizationHold {
+    pub fn new(client_id: u16, amount: Amount, expires_at: Option<u64>) -> Self {
+        Self {
+            client_id,
+            remaining: amount,
+            expires_at,
+        }
+    }
+
+    pub fn client_id(&self) -> u16 {
+        self.client_id
+    }
+
+    /// The amount that was neither captured nor released yet.
+    pub fn remaining(&self) -> Amount {
+        self.remaining
+    }
+
+    #[allow(dead_code)]
+    pub fn expires_at(&self) -> Option<u64> {
+        self.expires_at
+    }
+}
@@ -0,0 +1,70 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+use jellyfish_engine::errors::EngineError;
+use jellyfish_engine::workload::{Generator, WorkloadConfig};
+
+use clap::Parser;
+
+/// Generates a transactions csv for benchmarks, reproducible from its seed
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// Writes the rows to this file instead of stdout
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+    /// Writes the accounts the engine should print for the rows, ordered by client
+    #[arg(long)]
+    expected: Option<PathBuf>,
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    #[arg(long, default_value_t = 1000)]
+    clients: u16,
+    /// Rows below the header
+    #[arg(long, default_value_t = 100_000)]
+    transactions: u64,
+    /// Share of deposits and withdrawals that are withdrawals
+    #[arg(long, default_value_t = 0.4)]
+    withdrawal_ratio: f64,
+    /// Share of rows that dispute a recent deposit
+    #[arg(long, default_value_t = 0.01)]
+    dispute_rate: f64,
+    /// Share of rows that resolve an open dispute
+    #[arg(long, default_value_t = 0.005)]
+    resolve_rate: f64,
+    /// Share of rows that charge back an open dispute
+    #[arg(long, default_value_t = 0.002)]
+    chargeback_rate: f64,
+    /// Share of rows the engine rejects as invalid
+    #[arg(long, default_value_t = 0.001)]
+    invalid_rate: f64,
+    /// Share of rows with padded fields
+    #[arg(long, default_value_t = 0.1)]
+    whitespace_rate: f64,
+}
+
+fn main() -> Result<(), EngineError> {
+    let args = Args::parse();
+    let mut generator = Generator::new(WorkloadConfig {
+        clients: args.clients,
+        transactions: args.transactions,
+        withdrawal_ratio: args.withdrawal_ratio,
+        dispute_rate: args.dispute_rate,
+        resolve_rate: args.resolve_rate,
+        chargeback_rate: args.chargeback_rate,
+        invalid_rate: args.invalid_rate,
+        whitespace_rate: args.whitespace_rate,
+        seed: args.seed,
+    });
+
+    let output: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout().lock()),
+    };
+    generator.write_csv(BufWriter::new(output))?;
+    if let Some(path) = &args.expected {
+        generator.write_expected_accounts(BufWriter::new(File::create(path)?))?;
+    }
+    Ok(())
+}
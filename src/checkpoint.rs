@@ -0,0 +1,642 @@
+use crate::config::InvalidRows;
+use crate::errors::EngineError;
+use crate::input::{open_input, parse_rows, Compression, TransactionReader};
+use crate::ClientTransactionHandler;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Checkpoints of other versions are refused rather than misread.
+const VERSION: u32 = 3;
+
+/// Blocks of a file that go into its fingerprint.
+const SAMPLES: u64 = 16;
+const SAMPLE_LEN: u64 = 64 * 1024;
+
+/// Tells files apart even if they are renamed, the device and inode on unix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileId {
+    device: u64,
+    inode: u64,
+}
+
+impl FileId {
+    /// `None` where the platform has no inodes.
+    #[cfg(unix)]
+    pub fn of(metadata: &Metadata) -> Option<Self> {
+        use std::os::unix::fs::MetadataExt;
+        Some(Self {
+            device: metadata.dev(),
+            inode: metadata.ino(),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn of(_metadata: &Metadata) -> Option<Self> {
+        None
+    }
+}
+
+/// Tells whether a file still is the one a checkpoint was written for, from its
+/// length, modification time and a hash of 16 blocks of 64 KiB spread over it.
+/// Files up to 1 MiB are hashed whole, larger ones cost no more to check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    len: u64,
+    modified: Option<SystemTime>,
+    hash: u64,
+}
+
+impl Fingerprint {
+    pub fn of(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let metadata = file.metadata()?;
+        Self::sample(&mut file, metadata.len(), metadata.modified().ok())
+    }
+
+    /// The fingerprint of the first `len` bytes of a file that is still appended to,
+    /// without the modification time, which changes with every append.
+    pub fn of_prefix(file: &mut File, len: u64) -> io::Result<Self> {
+        Self::sample(file, len, None)
+    }
+
+    fn sample(file: &mut File, len: u64, modified: Option<SystemTime>) -> io::Result<Self> {
+        let mut sampled = Vec::new();
+        if len <= SAMPLES * SAMPLE_LEN {
+            file.seek(SeekFrom::Start(0))?;
+            file.take(len).read_to_end(&mut sampled)?;
+        } else {
+            // the first block starts the file and the last one ends it
+            for sample in 0..SAMPLES {
+                let offset = sample * (len - SAMPLE_LEN) / (SAMPLES - 1);
+                file.seek(SeekFrom::Start(offset))?;
+                file.take(SAMPLE_LEN).read_to_end(&mut sampled)?;
+            }
+        }
+        Ok(Self {
+            len,
+            modified,
+            hash: fnv1a(&sampled),
+        })
+    }
+}
+
+/// 64 bit FNV-1a, unlike `DefaultHasher` it stays the same across Rust versions.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// How far an input file was processed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InputPosition {
+    pub path: PathBuf,
+    pub file_id: Option<FileId>,
+    /// Batch inputs must not change before they are resumed, followed ones
+    /// must still start with the bytes that were read.
+    pub fingerprint: Option<Fingerprint>,
+    /// The offset of the next row.
+    pub byte: u64,
+    pub line: u64,
+    pub record: u64,
+    /// The batch inputs processed before this one, with their fingerprints.
+    pub completed: Vec<(PathBuf, Fingerprint)>,
+}
+
+impl InputPosition {
+    pub fn new(path: &Path, file_id: Option<FileId>, position: &csv::Position) -> Self {
+        let mut input = Self {
+            path: path.to_path_buf(),
+            file_id,
+            fingerprint: None,
+            byte: 0,
+            line: 0,
+            record: 0,
+            completed: Vec::new(),
+        };
+        input.advance(position);
+        input
+    }
+
+    /// Moves on to `position` in the same file.
+    pub fn advance(&mut self, position: &csv::Position) {
+        self.byte = position.byte();
+        self.line = position.line();
+        self.record = position.record();
+    }
+
+    pub fn csv_position(&self) -> csv::Position {
+        let mut position = csv::Position::new();
+        position
+            .set_byte(self.byte)
+            .set_line(self.line)
+            .set_record(self.record);
+        position
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    version: u32,
+    input: InputPosition,
+}
+
+impl Header {
+    fn new(input: &InputPosition) -> Self {
+        Self {
+            version: VERSION,
+            input: input.clone(),
+        }
+    }
+}
+
+/// Writes the position and the handler's state to `path`. The checkpoint is written
+/// next to it first and then renamed, so a crash leaves the previous one intact.
+pub fn write_checkpoint(
+    path: &Path,
+    input: &InputPosition,
+    handler: &ClientTransactionHandler,
+) -> Result<(), EngineError> {
+    let partial = path.with_extension("partial");
+    let mut out = BufWriter::new(File::create(&partial)?);
+    ciborium::into_writer(&Header::new(input), &mut out)
+        .map_err(|err| EngineError::Checkpoint(err.to_string()))?;
+    handler.save_state(&mut out)?;
+    out.flush()?;
+    out.get_ref().sync_all()?;
+    drop(out);
+    std::fs::rename(partial, path)?;
+    log::debug!(
+        "checkpoint written at line {} of {}",
+        input.line,
+        input.path.display()
+    );
+    Ok(())
+}
+
+/// Restores the handler's state from the checkpoint at `path`, along with the
+/// changes appended to it by a `Checkpointer`, and returns how far the input had
+/// been processed. A change cut short by a crash is left out.
+pub fn read_checkpoint(
+    path: &Path,
+    handler: &mut ClientTransactionHandler,
+) -> Result<InputPosition, EngineError> {
+    let file = File::open(path)
+        .map_err(|err| EngineError::Checkpoint(format!("{}: {}", path.display(), err)))?;
+    let mut input = BufReader::new(file);
+    let mut header = read_header(&mut input, path)?;
+    handler.restore_state(&mut input)?;
+    while let Some(change) = read_change(&mut input, path)? {
+        let mut change = change.as_slice();
+        header = read_header(&mut change, path)?;
+        handler.restore_changes(change)?;
+    }
+    Ok(header.input)
+}
+
+fn read_header<R: Read>(input: R, path: &Path) -> Result<Header, EngineError> {
+    let header: Header = ciborium::from_reader(input)
+        .map_err(|err| EngineError::Checkpoint(format!("{}: {}", path.display(), err)))?;
+    if header.version != VERSION {
+        return Err(EngineError::Checkpoint(format!(
+            "{} has version {}, expected {}",
+            path.display(),
+            header.version,
+            VERSION
+        )));
+    }
+    Ok(header)
+}
+
+/// The next change appended to a checkpoint, prefixed by its length.
+fn read_change<R: Read>(input: &mut R, path: &Path) -> Result<Option<Vec<u8>>, EngineError> {
+    let mut len = Vec::with_capacity(8);
+    input.take(8).read_to_end(&mut len)?;
+    if len.is_empty() {
+        return Ok(None);
+    }
+    let mut change = Vec::new();
+    if let Ok(len) = <[u8; 8]>::try_from(len.as_slice()) {
+        let len = u64::from_le_bytes(len);
+        input.take(len).read_to_end(&mut change)?;
+        if change.len() as u64 == len {
+            return Ok(Some(change));
+        }
+    }
+    log::warn!(
+        "the last change of {} is incomplete, resuming before it",
+        path.display()
+    );
+    Ok(None)
+}
+
+/// Writes the checkpoints of a run to one file. The first one saves the whole state,
+/// the following ones are appended to it and only save the transactions that changed
+/// since, so a checkpoint doesn't take longer the further the run got. Once the
+/// appended changes outgrow the whole state, it is written anew.
+pub struct Checkpointer {
+    path: PathBuf,
+    /// The file written last, to append the next changes to.
+    file: Option<File>,
+    state_len: u64,
+    changes_len: u64,
+}
+
+impl Checkpointer {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            file: None,
+            state_len: 0,
+            changes_len: 0,
+        }
+    }
+
+    pub fn write(
+        &mut self,
+        input: &InputPosition,
+        handler: &mut ClientTransactionHandler,
+    ) -> Result<(), EngineError> {
+        let file = match self.file.as_mut() {
+            Some(file) if self.changes_len < self.state_len => file,
+            _ => {
+                write_checkpoint(&self.path, input, handler)?;
+                handler.track_unsaved_changes();
+                let file = OpenOptions::new().append(true).open(&self.path)?;
+                (self.state_len, self.changes_len) = (file.metadata()?.len(), 0);
+                self.file = Some(file);
+                return Ok(());
+            }
+        };
+        let mut change = Vec::new();
+        ciborium::into_writer(&Header::new(input), &mut change)
+            .map_err(|err| EngineError::Checkpoint(err.to_string()))?;
+        handler.save_changes(&mut change)?;
+        let len = change.len() as u64;
+        file.write_all(&len.to_le_bytes())?;
+        file.write_all(&change)?;
+        file.sync_data()?;
+        self.changes_len += 8 + len;
+        log::debug!(
+            "checkpoint changes appended at line {} of {}",
+            input.line,
+            input.path.display()
+        );
+        Ok(())
+    }
+}
+
+/// How a batch run writes checkpoints.
+#[derive(Debug, Clone)]
+pub struct CheckpointOptions {
+    pub path: PathBuf,
+    /// Rows of a file between two checkpoints, one is also written at the end of every file.
+    pub every: u64,
+    /// Goes on from the checkpoint at `path` instead of starting over.
+    pub resume: bool,
+}
+
+/// Processes the files like `input::parse_files` and writes checkpoints along the way.
+///
+/// When resuming, the files before the one in the checkpoint count as done. Resuming
+/// is refused if they are not the ones the checkpoint completed, or if the fingerprint
+/// of any of them or of the one in the checkpoint changed. Plain files continue at the
+/// saved offset, compressed ones are decompressed again up to it.
+pub fn parse_files_with_checkpoints(
+    files: &[PathBuf],
+    invalid_rows: InvalidRows,
+    handler: &mut ClientTransactionHandler,
+    options: &CheckpointOptions,
+) -> Result<(), EngineError> {
+    let mut checkpointer = Checkpointer::new(&options.path);
+    let (mut first, mut start, mut completed) = (0, csv::Position::new(), Vec::new());
+    if options.resume {
+        let saved = read_checkpoint(&options.path, handler)?;
+        let source = saved.path.display();
+        first = files
+            .iter()
+            .position(|path| *path == saved.path)
+            .ok_or_else(|| EngineError::Checkpoint(format!("{} is not an input", source)))?;
+        let done = saved.completed.iter().map(|(path, _)| path);
+        if !done.eq(&files[..first]) {
+            return Err(EngineError::Checkpoint(format!(
+                "the inputs before {} are not the ones the checkpoint completed",
+                source
+            )));
+        }
+        for (path, fingerprint) in &saved.completed {
+            ensure_unchanged(path, Some(*fingerprint))?;
+        }
+        ensure_unchanged(&saved.path, saved.fingerprint)?;
+        log::info!("resuming at line {} of {}", saved.line, source);
+        start = saved.csv_position();
+        completed = saved.completed;
+    }
+    for path in &files[first..] {
+        let fingerprint = parse_file_with_checkpoints(
+            path,
+            &start,
+            &completed,
+            invalid_rows,
+            handler,
+            options,
+            &mut checkpointer,
+        )?;
+        completed.push((path.clone(), fingerprint));
+        start = csv::Position::new();
+    }
+    Ok(())
+}
+
+fn ensure_unchanged(path: &Path, fingerprint: Option<Fingerprint>) -> Result<(), EngineError> {
+    let current = Fingerprint::of(path)
+        .map_err(|err| EngineError::Checkpoint(format!("{}: {}", path.display(), err)))?;
+    if fingerprint != Some(current) {
+        return Err(EngineError::Checkpoint(format!(
+            "{} changed since the checkpoint, refusing to resume",
+            path.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Processes one file from `start` and returns its fingerprint.
+fn parse_file_with_checkpoints(
+    path: &Path,
+    start: &csv::Position,
+    completed: &[(PathBuf, Fingerprint)],
+    invalid_rows: InvalidRows,
+    handler: &mut ClientTransactionHandler,
+    options: &CheckpointOptions,
+    checkpointer: &mut Checkpointer,
+) -> Result<Fingerprint, EngineError> {
+    let source = path.display().to_string();
+    log::debug!("reading {}", source);
+    let file = File::open(path)
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", source, err)))?;
+    let fingerprint = Fingerprint::of(path)?;
+    let mut input = InputPosition::new(path, FileId::of(&file.metadata()?), start);
+    input.fingerprint = Some(fingerprint);
+    input.completed = completed.to_vec();
+    let every = options.every.max(1);
+    if Compression::of_file(path)? == Compression::None {
+        let mut reader = TransactionReader::resume(file, start)?;
+        checkpoint_rows(
+            &mut reader,
+            &mut input,
+            every,
+            invalid_rows,
+            handler,
+            checkpointer,
+        )?;
+    } else {
+        let mut reader = TransactionReader::new(open_input(path)?)?;
+        reader.skip_to(start)?;
+        checkpoint_rows(
+            &mut reader,
+            &mut input,
+            every,
+            invalid_rows,
+            handler,
+            checkpointer,
+        )?;
+    }
+    Ok(fingerprint)
+}
+
+fn checkpoint_rows<R: Read>(
+    reader: &mut TransactionReader<R>,
+    input: &mut InputPosition,
+    every: u64,
+    invalid_rows: InvalidRows,
+    handler: &mut ClientTransactionHandler,
+    checkpointer: &mut Checkpointer,
+) -> Result<(), EngineError> {
+    let source = input.path.display().to_string();
+    loop {
+        let rows = parse_rows(reader, every, &source, invalid_rows, handler)?;
+        input.advance(&reader.position());
+        checkpointer.write(input, handler)?;
+        if rows < every {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        parse_files_with_checkpoints, read_checkpoint, write_checkpoint, CheckpointOptions,
+        Checkpointer, InputPosition,
+    };
+    use crate::config::InvalidRows;
+    use crate::errors::EngineError;
+    use crate::input::{parse_files, parse_transactions};
+    use crate::ClientTransactionHandler;
+    use flate2::write::GzEncoder;
+    use std::fs;
+    use std::io::Write;
+
+    fn accounts(handler: &ClientTransactionHandler) -> String {
+        let mut clients: Vec<_> = handler.clients().values().collect();
+        clients.sort_by_key(|client| client.id());
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        for client in clients {
+            wtr.serialize(client).unwrap();
+        }
+        String::from_utf8(wtr.into_inner().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn a_restored_handler_goes_on_like_the_original() {
+        let before = "type,client,tx,amount,timestamp\n\
+            deposit,1,1,10.0,100\n\
+            authorize,1,2,3.0,110\n\
+            deposit,2,3,5.0,120\n\
+            dispute,2,3,,130\n\
+            pending_deposit,1,4,2.0,140\n";
+        let after = "type,client,tx,amount,timestamp\n\
+            capture,1,2,,150\n\
+            chargeback,2,3,,160\n\
+            settle,1,4,,170\n\
+            deposit,1,5,1.0,180\n\
+            deposit,2,6,1.0,190\n";
+        let mut original = ClientTransactionHandler::new();
+        original.enable_history();
+        parse_transactions(before.as_bytes(), "test", InvalidRows::Fail, &mut original).unwrap();
+
+        let path =
+            std::env::temp_dir().join(format!("jellyfish-{}.checkpoint", std::process::id()));
+        let position = InputPosition {
+            path: "input.csv".into(),
+            file_id: None,
+            fingerprint: None,
+            byte: 120,
+            line: 6,
+            record: 5,
+            completed: Vec::new(),
+        };
+        write_checkpoint(&path, &position, &original).unwrap();
+        let mut restored = ClientTransactionHandler::new();
+        assert_eq!(read_checkpoint(&path, &mut restored).unwrap(), position);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(accounts(&restored), accounts(&original));
+        assert_eq!(restored.sequence(), original.sequence());
+
+        for handler in [&mut original, &mut restored] {
+            parse_transactions(after.as_bytes(), "test", InvalidRows::Fail, handler).unwrap();
+            handler.check_trial_balance().unwrap();
+        }
+        assert_eq!(accounts(&restored), accounts(&original));
+        assert!(restored.clients().get(&2).unwrap().locked());
+        assert_eq!(restored.ledger().journal(), original.ledger().journal());
+        assert!(!restored.ledger().journal().is_empty());
+    }
+
+    #[test]
+    fn checkpoints_append_the_changed_transactions() {
+        // enough transactions that the changes stay smaller than the whole state
+        let deposits: String = (10..100)
+            .map(|tx| format!("deposit,{},{},1.0\n", tx % 7 + 1, tx))
+            .collect();
+        let parts = [
+            format!("type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5.0\n{deposits}"),
+            "type,client,tx,amount\ndispute,1,1,\ndeposit,1,3,1.0\n".to_string(),
+            "type,client,tx,amount\nresolve,1,1,\ndispute,2,2,\n".to_string(),
+        ];
+        let path = std::env::temp_dir().join(format!(
+            "jellyfish-{}-appended.checkpoint",
+            std::process::id()
+        ));
+        let mut handler = ClientTransactionHandler::new();
+        let mut checkpointer = Checkpointer::new(&path);
+        let mut lens = Vec::new();
+        for (line, part) in parts.iter().enumerate() {
+            parse_transactions(part.as_bytes(), "test", InvalidRows::Fail, &mut handler).unwrap();
+            let position = InputPosition {
+                path: "input.csv".into(),
+                file_id: None,
+                fingerprint: None,
+                byte: 0,
+                line: line as u64,
+                record: 0,
+                completed: Vec::new(),
+            };
+            checkpointer.write(&position, &mut handler).unwrap();
+            lens.push(fs::metadata(&path).unwrap().len());
+        }
+        // the second and third checkpoints were appended
+        assert!(lens[0] < lens[1] && lens[1] < lens[2]);
+
+        // a change cut short is left out
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&100u64.to_le_bytes()).unwrap();
+        file.write_all(b"cut").unwrap();
+        drop(file);
+
+        let mut restored = ClientTransactionHandler::new();
+        assert_eq!(read_checkpoint(&path, &mut restored).unwrap().line, 2);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(accounts(&restored), accounts(&handler));
+        for handler in [&mut handler, &mut restored] {
+            let rows = "type,client,tx,amount\ndispute,1,1,\nchargeback,2,2,\n";
+            parse_transactions(rows.as_bytes(), "test", InvalidRows::Fail, handler).unwrap();
+            handler.check_trial_balance().unwrap();
+        }
+        assert_eq!(accounts(&restored), accounts(&handler));
+    }
+
+    #[test]
+    fn a_batch_run_resumes_after_the_last_checkpoint() {
+        let dir = std::env::temp_dir().join(format!("jellyfish-resume-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (plain, gzip) = (dir.join("part-1.csv"), dir.join("part-2.csv.gz"));
+        let files = [plain.clone(), gzip.clone()];
+        let rows = [
+            "deposit,1,1,1.0",
+            "deposit,2,2,2.0",
+            "withdrawal,1,3,0.5",
+            "deposit,1,4,4.0",
+            "dispute,2,2,",
+            "deposit,2,5,8.0",
+        ];
+
+        // the run gets killed by a bad row after the second checkpoint of either file
+        for bad in [0, 1] {
+            let mut parts = [rows[..3].to_vec(), rows[3..].to_vec()];
+            parts[bad].insert(2, "deposit,x,9,1.0");
+            let csv = |rows: &[&str]| format!("type,client,tx,amount\n{}\n", rows.join("\n"));
+            fs::write(&plain, csv(&parts[0])).unwrap();
+            let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+            encoder.write_all(csv(&parts[1]).as_bytes()).unwrap();
+            fs::write(&gzip, encoder.finish().unwrap()).unwrap();
+
+            let mut options = CheckpointOptions {
+                path: dir.join("checkpoint"),
+                every: 2,
+                resume: false,
+            };
+            let mut killed = ClientTransactionHandler::new();
+            parse_files_with_checkpoints(&files, InvalidRows::Fail, &mut killed, &options)
+                .unwrap_err();
+            let saved = read_checkpoint(&options.path, &mut ClientTransactionHandler::new());
+            let saved = saved.unwrap();
+            assert_eq!((&saved.path, saved.line), (&files[bad], 4));
+
+            options.resume = true;
+            let mut resumed = ClientTransactionHandler::new();
+            parse_files_with_checkpoints(&files, InvalidRows::Skip, &mut resumed, &options)
+                .unwrap();
+            let mut whole = ClientTransactionHandler::new();
+            parse_files(&files, InvalidRows::Skip, &mut whole).unwrap();
+            assert_eq!(accounts(&resumed), accounts(&whole));
+            assert_eq!(resumed.ledger().journal(), whole.ledger().journal());
+        }
+
+        // so is a changed input that was completed before the one in the checkpoint
+        let saved = read_checkpoint(
+            &dir.join("checkpoint"),
+            &mut ClientTransactionHandler::new(),
+        );
+        assert_eq!(saved.unwrap().path, gzip);
+        let options = CheckpointOptions {
+            path: dir.join("checkpoint"),
+            every: 2,
+            resume: true,
+        };
+        let part_1 = (fs::read(&plain).unwrap(), fs::metadata(&plain).unwrap());
+        fs::write(&plain, "type,client,tx,amount\ndeposit,1,1,100.0\n").unwrap();
+        let mut handler = ClientTransactionHandler::new();
+        let result =
+            parse_files_with_checkpoints(&files, InvalidRows::Skip, &mut handler, &options);
+        assert!(matches!(result, Err(EngineError::Checkpoint(_))));
+        // or one left out of the inputs
+        let mut handler = ClientTransactionHandler::new();
+        let result =
+            parse_files_with_checkpoints(&files[1..], InvalidRows::Skip, &mut handler, &options);
+        assert!(matches!(result, Err(EngineError::Checkpoint(_))));
+        fs::write(&plain, part_1.0).unwrap();
+        let file = fs::File::options().write(true).open(&plain).unwrap();
+        file.set_modified(part_1.1.modified().unwrap()).unwrap();
+        drop(file);
+        let mut handler = ClientTransactionHandler::new();
+        parse_files_with_checkpoints(&files, InvalidRows::Skip, &mut handler, &options).unwrap();
+
+        // a changed input is refused
+        fs::write(&gzip, "type,client,tx,amount\ndeposit,1,4,4.0\n").unwrap();
+        let options = CheckpointOptions {
+            path: dir.join("checkpoint"),
+            every: 2,
+            resume: true,
+        };
+        let mut handler = ClientTransactionHandler::new();
+        let result =
+            parse_files_with_checkpoints(&files, InvalidRows::Skip, &mut handler, &options);
+        assert!(matches!(result, Err(EngineError::Checkpoint(_))));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,30 +1,108 @@
+use crate::amount::Amount;
 use crate::errors::TransactionError;
-use serde::Serialize;
+use crate::ledger::{Account, ClientBalances, Transfer};
+use crate::risk::{RiskAction, RiskCounters, RiskPolicy, RiskReportRow};
+use serde::{Deserialize, Serialize};
 use std::fmt;
 
+/// The reason funds are held. Both kinds share the same machinery,
+/// but are kept in separate buckets so they can be reported separately.
+#[derive(Debug, Clone, Copy)]
+enum HoldKind {
+    Dispute,
+    Authorization,
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct Client {
     #[serde(rename = "client")]
     id: u16,
-    available: f64,
-    held: f64,
-    total: f64,
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: bool,
+    authorized: Amount,
+    pending: Amount,
+    #[serde(skip)]
+    frozen: bool,
+    #[serde(skip)]
+    flagged: bool,
+    #[serde(skip)]
+    risk: RiskCounters,
+}
+
+/// Everything a client is made of, for checkpoints. The `Serialize` of `Client`
+/// is its output row, which leaves the risk state out.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientState {
+    id: u16,
+    available: Amount,
+    held: Amount,
+    total: Amount,
     locked: bool,
+    authorized: Amount,
+    pending: Amount,
+    frozen: bool,
+    flagged: bool,
+    risk: RiskCounters,
+}
+
+impl From<&Client> for ClientState {
+    fn from(client: &Client) -> Self {
+        Self {
+            id: client.id,
+            available: client.available,
+            held: client.held,
+            total: client.total,
+            locked: client.locked,
+            authorized: client.authorized,
+            pending: client.pending,
+            frozen: client.frozen,
+            flagged: client.flagged,
+            risk: client.risk.clone(),
+        }
+    }
+}
+
+impl From<ClientState> for Client {
+    fn from(state: ClientState) -> Self {
+        Self {
+            id: state.id,
+            available: state.available,
+            held: state.held,
+            total: state.total,
+            locked: state.locked,
+            authorized: state.authorized,
+            pending: state.pending,
+            frozen: state.frozen,
+            flagged: state.flagged,
+            risk: state.risk,
+        }
+    }
 }
 
 impl Client {
     pub fn from_id(id: u16) -> Self {
         Self {
             id,
-            available: 0.0,
-            held: 0.0,
-            total: 0.0,
+            available: Amount::ZERO,
+            held: Amount::ZERO,
+            total: Amount::ZERO,
             locked: false,
+            authorized: Amount::ZERO,
+            pending: Amount::ZERO,
+            frozen: false,
+            flagged: false,
+            risk: RiskCounters::default(),
         }
     }
 
-    pub fn total(&self) -> f64 {
-        self.available + self.held
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn total(&self) -> Amount {
+        self.available + self.held + self.authorized
     }
 
     fn update_total(&mut self) {
@@ -32,102 +110,409 @@ impl Client {
     }
 
     #[allow(dead_code)]
-    pub fn available(&self) -> f64 {
+    pub fn available(&self) -> Amount {
         self.available
     }
 
     #[allow(dead_code)]
-    pub fn held(&self) -> f64 {
+    pub fn held(&self) -> Amount {
         self.held
     }
 
-    fn is_locked(&self) -> Result<(), TransactionError> {
-        if self.locked {
-            Err(TransactionError::ClientIsLocked(self.id))
-        } else {
-            Ok(())
-        }
+    /// Funds reserved by authorization holds, they are not part of `held`.
+    pub fn authorized(&self) -> Amount {
+        self.authorized
     }
 
-    /// Adds `amount` to the clients available funds and returns the new available amount.
-    pub fn deposit(&mut self, amount: f64) -> Result<f64, TransactionError> {
-        self.is_locked()?;
-        self.available += amount;
-        self.update_total();
-        Ok(self.available)
+    /// Deposits that have not cleared yet, they are not part of `total`.
+    pub fn pending(&self) -> Amount {
+        self.pending
     }
 
-    /// Withdraws `amount` from the clients available funds and returns the new available amount.
-    pub fn withdraw(&mut self, amount: f64) -> Result<f64, TransactionError> {
-        self.is_locked()?;
-        if amount > self.available {
-            Err(TransactionError::AmountNotAvailable {
-                client_id: self.id,
-                amount,
-            })
-        } else {
-            self.available -= amount;
-            self.update_total();
-            Ok(self.available)
+    pub fn balances(&self) -> ClientBalances {
+        ClientBalances {
+            available: self.available,
+            held: self.held,
+            authorized: self.authorized,
+            pending: self.pending,
         }
     }
 
-    pub fn lock(&mut self) -> Result<(), TransactionError> {
-        if self.locked {
-            Err(TransactionError::ClientLockFailed(self.id))
-        } else {
-            self.locked = true;
-            Ok(())
-        }
+    pub fn locked(&self) -> bool {
+        self.locked
     }
 
     #[allow(dead_code)]
-    pub fn unlock(&mut self) -> Result<(), TransactionError> {
-        if !self.locked {
-            Err(TransactionError::ClientUnlockFailed(self.id))
-        } else {
-            self.locked = false;
-            Ok(())
-        }
+    pub fn frozen(&self) -> bool {
+        self.frozen
+    }
+
+    #[allow(dead_code)]
+    pub fn flagged(&self) -> bool {
+        self.flagged
     }
 
-    pub fn dispute(&mut self, amount: f64) -> Result<(), TransactionError> {
-        if amount > self.available {
-            Err(TransactionError::AmountNotAvailable {
+    #[allow(dead_code)]
+    pub fn risk(&self) -> &RiskCounters {
+        &self.risk
+    }
+
+    fn is_locked(&self, tx_id: u32) -> Result<(), TransactionError> {
+        if self.locked {
+            Err(TransactionError::ClientIsLocked {
+                tx_id,
                 client_id: self.id,
-                amount,
             })
         } else {
-            self.available -= amount;
-            self.held += amount;
             Ok(())
         }
     }
 
-    pub fn resolve(&mut self, amount: f64) -> Result<(), TransactionError> {
-        if amount > self.held {
-            Err(TransactionError::AmountNotHeld {
+    fn is_frozen(&self, tx_id: u32) -> Result<(), TransactionError> {
+        if self.frozen {
+            Err(TransactionError::ClientIsFrozen {
+                tx_id,
                 client_id: self.id,
-                amount,
             })
         } else {
-            self.available += amount;
-            self.held -= amount;
             Ok(())
         }
     }
 
-    pub fn chargeback(&mut self, amount: f64) -> Result<(), TransactionError> {
-        if amount > self.held {
-            Err(TransactionError::AmountNotHeld {
-                client_id: self.id,
+    /// The clients balances cache the ledger balances of its accounts, they change
+    /// here only, with the same transfers that are booked in the ledger.
+    fn apply(&mut self, transfer: Transfer) -> Transfer {
+        if let Some(balance) = self.balance_mut(transfer.debit) {
+            *balance -= transfer.amount;
+        }
+        if let Some(balance) = self.balance_mut(transfer.credit) {
+            *balance += transfer.amount;
+        }
+        self.update_total();
+        transfer
+    }
+
+    fn balance_mut(&mut self, account: Account) -> Option<&mut Amount> {
+        match account {
+            Account::ClientAvailable(id) if id == self.id => Some(&mut self.available),
+            Account::ClientHeld(id) if id == self.id => Some(&mut self.held),
+            Account::ClientAuthorized(id) if id == self.id => Some(&mut self.authorized),
+            Account::ClientPending(id) if id == self.id => Some(&mut self.pending),
+            _ => None,
+        }
+    }
+
+    /// Checks that `amount` is on `account` before it is debited.
+    fn ensure_funds(
+        &self,
+        tx_id: u32,
+        account: Account,
+        amount: Amount,
+    ) -> Result<(), TransactionError> {
+        let client_id = self.id;
+        match account {
+            Account::ClientAvailable(_) if amount > self.available => {
+                Err(TransactionError::AmountNotAvailable {
+                    tx_id,
+                    client_id,
+                    amount,
+                    available: self.available,
+                })
+            }
+            Account::ClientHeld(_) if amount > self.held => Err(TransactionError::AmountNotHeld {
+                tx_id,
+                client_id,
                 amount,
-            })
-        } else {
-            self.held -= amount;
-            self.update_total();
-            self.lock()?;
-            Ok(())
+                held: self.held,
+            }),
+            Account::ClientAuthorized(_) if amount > self.authorized => {
+                Err(TransactionError::AmountNotHeld {
+                    tx_id,
+                    client_id,
+                    amount,
+                    held: self.authorized,
+                })
+            }
+            Account::ClientPending(_) if amount > self.pending => {
+                Err(TransactionError::AmountNotPending {
+                    tx_id,
+                    client_id,
+                    amount,
+                    pending: self.pending,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Moves `amount` from the `debit` to the `credit` account if the funds are there.
+    fn transfer(
+        &mut self,
+        tx_id: u32,
+        debit: Account,
+        credit: Account,
+        amount: Amount,
+    ) -> Result<Transfer, TransactionError> {
+        self.ensure_funds(tx_id, debit, amount)?;
+        Ok(self.apply(Transfer::new(debit, credit, amount)))
+    }
+
+    /// Adds `amount` to the clients available funds.
+    pub fn deposit(&mut self, tx_id: u32, amount: Amount) -> Result<Transfer, TransactionError> {
+        self.is_locked(tx_id)?;
+        let transfer = self.transfer(
+            tx_id,
+            Account::ExternalClearing,
+            Account::ClientAvailable(self.id),
+            amount,
+        )?;
+        self.risk.record_deposit();
+        Ok(transfer)
+    }
+
+    /// Adds `amount` to the clients pending funds until it is settled or returned.
+    pub fn deposit_pending(
+        &mut self,
+        tx_id: u32,
+        amount: Amount,
+    ) -> Result<Transfer, TransactionError> {
+        self.is_locked(tx_id)?;
+        self.transfer(
+            tx_id,
+            Account::ExternalClearing,
+            Account::ClientPending(self.id),
+            amount,
+        )
+    }
+
+    /// Moves `amount` from the pending to the available funds.
+    pub fn settle(&mut self, tx_id: u32, amount: Amount) -> Result<Transfer, TransactionError> {
+        let transfer = self.transfer(
+            tx_id,
+            Account::ClientPending(self.id),
+            Account::ClientAvailable(self.id),
+            amount,
+        )?;
+        self.risk.record_deposit();
+        Ok(transfer)
+    }
+
+    /// Removes `amount` from the pending funds without crediting it.
+    pub fn return_pending(
+        &mut self,
+        tx_id: u32,
+        amount: Amount,
+    ) -> Result<Transfer, TransactionError> {
+        self.transfer(
+            tx_id,
+            Account::ClientPending(self.id),
+            Account::ExternalClearing,
+            amount,
+        )
+    }
+
+    /// Withdraws `amount` from the clients available funds.
+    pub fn withdraw(&mut self, tx_id: u32, amount: Amount) -> Result<Transfer, TransactionError> {
+        self.is_locked(tx_id)?;
+        self.is_frozen(tx_id)?;
+        self.transfer(
+            tx_id,
+            Account::ClientAvailable(self.id),
+            Account::ExternalClearing,
+            amount,
+        )
+    }
+
+    /// Takes back `amount` of a deposit from the clients available funds.
+    pub fn reverse_deposit(
+        &mut self,
+        tx_id: u32,
+        amount: Amount,
+    ) -> Result<Transfer, TransactionError> {
+        self.transfer(
+            tx_id,
+            Account::ClientAvailable(self.id),
+            Account::ExternalClearing,
+            amount,
+        )
+    }
+
+    /// Gives `amount` of a withdrawal back to the clients available funds.
+    pub fn refund(&mut self, tx_id: u32, amount: Amount) -> Result<Transfer, TransactionError> {
+        self.transfer(
+            tx_id,
+            Account::ExternalClearing,
+            Account::ClientAvailable(self.id),
+            amount,
+        )
+    }
+
+    pub fn lock(&mut self) {
+        self.locked = true;
+    }
+
+    #[allow(dead_code)]
+    pub fn unlock(&mut self) {
+        self.locked = false;
+    }
+
+    fn held_account(&self, kind: HoldKind) -> Account {
+        match kind {
+            HoldKind::Dispute => Account::ClientHeld(self.id),
+            HoldKind::Authorization => Account::ClientAuthorized(self.id),
+        }
+    }
+
+    /// Moves `amount` from the available funds to the held funds of `kind`.
+    fn hold(
+        &mut self,
+        tx_id: u32,
+        amount: Amount,
+        kind: HoldKind,
+    ) -> Result<Transfer, TransactionError> {
+        let held = self.held_account(kind);
+        self.transfer(tx_id, Account::ClientAvailable(self.id), held, amount)
+    }
+
+    /// Moves `amount` from the held funds of `kind` back to the available funds.
+    fn release(
+        &mut self,
+        tx_id: u32,
+        amount: Amount,
+        kind: HoldKind,
+    ) -> Result<Transfer, TransactionError> {
+        let held = self.held_account(kind);
+        self.transfer(tx_id, held, Account::ClientAvailable(self.id), amount)
+    }
+
+    /// Moves `amount` from available to held funds and applies the
+    /// `policy` to the updated dispute history.
+    pub fn dispute(
+        &mut self,
+        tx_id: u32,
+        amount: Amount,
+        policy: &RiskPolicy,
+    ) -> Result<Transfer, TransactionError> {
+        let transfer = self.hold(tx_id, amount, HoldKind::Dispute)?;
+        self.risk.record_dispute();
+        self.apply_risk_policy(policy);
+        Ok(transfer)
+    }
+
+    pub fn resolve(&mut self, tx_id: u32, amount: Amount) -> Result<Transfer, TransactionError> {
+        self.release(tx_id, amount, HoldKind::Dispute)
+    }
+
+    /// Books `amount` of the held funds as a chargeback loss and applies the
+    /// `policy` to the updated dispute history.
+    pub fn chargeback(
+        &mut self,
+        tx_id: u32,
+        amount: Amount,
+        policy: &RiskPolicy,
+    ) -> Result<Transfer, TransactionError> {
+        let transfer = self.transfer(
+            tx_id,
+            Account::ClientHeld(self.id),
+            Account::ChargebackLosses,
+            amount,
+        )?;
+        self.risk.record_chargeback();
+        self.apply_risk_policy(policy);
+        Ok(transfer)
+    }
+
+    /// Holds `amount` of a disputed withdrawal until the dispute is settled and applies
+    /// the `policy` to the updated dispute history.
+    pub fn dispute_withdrawal(
+        &mut self,
+        tx_id: u32,
+        amount: Amount,
+        policy: &RiskPolicy,
+    ) -> Result<Transfer, TransactionError> {
+        let transfer = self.transfer(
+            tx_id,
+            Account::ExternalClearing,
+            Account::ClientHeld(self.id),
+            amount,
+        )?;
+        self.risk.record_dispute();
+        self.apply_risk_policy(policy);
+        Ok(transfer)
+    }
+
+    /// The withdrawal stands, the held `amount` goes back out.
+    pub fn resolve_withdrawal(
+        &mut self,
+        tx_id: u32,
+        amount: Amount,
+    ) -> Result<Transfer, TransactionError> {
+        self.transfer(
+            tx_id,
+            Account::ClientHeld(self.id),
+            Account::ExternalClearing,
+            amount,
+        )
+    }
+
+    /// The withdrawal is undone, the held `amount` is returned to the available funds.
+    /// Unlike a deposit chargeback this does not count against the client.
+    pub fn chargeback_withdrawal(
+        &mut self,
+        tx_id: u32,
+        amount: Amount,
+    ) -> Result<Transfer, TransactionError> {
+        self.transfer(
+            tx_id,
+            Account::ClientHeld(self.id),
+            Account::ClientAvailable(self.id),
+            amount,
+        )
+    }
+
+    /// Reserves `amount` of the available funds for a later capture.
+    pub fn authorize(&mut self, tx_id: u32, amount: Amount) -> Result<Transfer, TransactionError> {
+        self.is_locked(tx_id)?;
+        self.is_frozen(tx_id)?;
+        self.hold(tx_id, amount, HoldKind::Authorization)
+    }
+
+    /// Settles `amount` of the authorized funds as a withdrawal.
+    pub fn capture(&mut self, tx_id: u32, amount: Amount) -> Result<Transfer, TransactionError> {
+        self.is_locked(tx_id)?;
+        self.transfer(
+            tx_id,
+            Account::ClientAuthorized(self.id),
+            Account::ExternalClearing,
+            amount,
+        )
+    }
+
+    /// Releases `amount` of the authorized funds back to the available funds.
+    pub fn void(&mut self, tx_id: u32, amount: Amount) -> Result<Transfer, TransactionError> {
+        self.release(tx_id, amount, HoldKind::Authorization)
+    }
+
+    fn apply_risk_policy(&mut self, policy: &RiskPolicy) {
+        match policy.evaluate(&self.risk) {
+            Some(RiskAction::Lock) => self.locked = true,
+            Some(RiskAction::Freeze) => self.frozen = true,
+            Some(RiskAction::Flag) => self.flagged = true,
+            None => {}
+        }
+    }
+
+    pub fn risk_report(&self) -> RiskReportRow {
+        RiskReportRow {
+            client: self.id,
+            deposits: self.risk.deposits(),
+            disputes: self.risk.disputes(),
+            chargebacks: self.risk.chargebacks(),
+            dispute_ratio: self.risk.dispute_ratio(),
+            chargeback_ratio: self.risk.chargeback_ratio(),
+            flagged: self.flagged,
+            frozen: self.frozen,
+            locked: self.locked,
         }
     }
 }
@@ -136,10 +521,12 @@ impl fmt::Display for Client {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "Client {}: available: {:.4}, held: {:.4}, total: {:.4}, locked: {}",
+            "Client {}: available: {}, held: {}, authorized: {}, pending: {}, total: {}, locked: {}",
             self.id,
             self.available,
             self.held,
+            self.authorized,
+            self.pending,
             self.total(),
             self.locked
         )
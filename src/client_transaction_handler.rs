@@ -1,143 +1,1095 @@
-use crate::errors::TransactionError;
-use crate::transaction::{Transaction, TxType};
+use crate::amount::Amount;
+use crate::authorization::AuthorizationHold;
+use crate::client::ClientState;
+use crate::config::DisputeConfig;
+use crate::errors::{EngineError, TransactionError, TxState};
+use crate::events::{AccountEvent, EventStream};
+use crate::ledger::{ClientBalances, Ledger, PointInTime};
+use crate::metrics::{Gauges, Metrics, MetricsEndpoint};
+use crate::observer::TransactionObserver;
+use crate::risk::{RiskPolicy, RiskReportRow};
+use crate::rules::{RuleDecision, RulesEngine};
+use crate::schedule::Schedule;
+use crate::statement::{StatementFilter, StatementLine};
+use crate::transaction::{StoredTransaction, Transaction, TxType};
+use crate::validation::{ValidationContext, ValidationPipeline, Validator};
 use crate::Client;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+/// Number of recent transactions per client that are handed to the rules.
+const RULES_HISTORY_LEN: usize = 32;
+
+/// The metrics endpoint is updated after this many transactions.
+const METRICS_PUBLISH_INTERVAL: u64 = 1000;
 
 /// The ClientTransactionHandler implements the core logic of the jellyfish engine.
 /// it handles transactions and updates client objects according to the requirements.
 pub struct ClientTransactionHandler {
-    transactions: HashMap<u32, Transaction>,
+    transactions: HashMap<u32, StoredTransaction>,
+    /// Ids of charged back transactions that were not kept, they can't be used again.
+    dropped_ids: HashSet<u32>,
+    /// Ids of the transactions added, changed or removed since the state was last
+    /// saved, only tracked once `track_unsaved_changes` was called.
+    unsaved: Option<HashSet<u32>>,
     clients: HashMap<u16, Client>,
+    rules: Option<RulesEngine>,
+    history: HashMap<u16, VecDeque<Transaction>>,
+    held_for_review: Vec<Transaction>,
+    risk_policy: RiskPolicy,
+    disputes: DisputeConfig,
+    holds: HashMap<u32, AuthorizationHold>,
+    hold_expiries: Schedule,
+    hold_ttl: Option<u64>,
+    clearing: Schedule,
+    clearing_delay: Option<u64>,
+    /// The latest transaction timestamp seen so far.
+    now: Option<u64>,
+    ledger: Ledger,
+    /// The number of transactions added so far.
+    sequence: u64,
+    /// Every row per client, only kept once statements are enabled.
+    statements: Option<HashMap<u16, Vec<StatementLine>>>,
+    events: Option<EventStream>,
+    observers: Vec<Box<dyn TransactionObserver>>,
+    validators: ValidationPipeline,
+    metrics: Option<Metrics>,
+    metrics_endpoint: Option<MetricsEndpoint>,
+}
+
+/// The part of the handler a checkpoint keeps. Configuration, rules, validators,
+/// observers, event streams, statements and metrics belong to the process and are
+/// set up again by whoever restores the state.
+///
+/// `T` are all transactions, or only the ones changed since the last save with
+/// `None` for the removed ones.
+#[derive(Serialize)]
+struct SavedState<'a, T> {
+    clients: Vec<ClientState>,
+    transactions: T,
+    dropped_ids: &'a HashSet<u32>,
+    history: &'a HashMap<u16, VecDeque<Transaction>>,
+    held_for_review: &'a [Transaction],
+    holds: &'a HashMap<u32, AuthorizationHold>,
+    hold_expiries: &'a Schedule,
+    clearing: &'a Schedule,
+    now: Option<u64>,
+    ledger: &'a Ledger,
+    sequence: u64,
+}
+
+/// A `SavedState` read back.
+#[derive(Deserialize)]
+struct RestoredState<T> {
+    clients: Vec<ClientState>,
+    transactions: T,
+    #[serde(default)]
+    dropped_ids: HashSet<u32>,
+    history: HashMap<u16, VecDeque<Transaction>>,
+    held_for_review: Vec<Transaction>,
+    holds: HashMap<u32, AuthorizationHold>,
+    hold_expiries: Schedule,
+    clearing: Schedule,
+    now: Option<u64>,
+    ledger: Ledger,
+    sequence: u64,
+}
+
+impl Default for ClientTransactionHandler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ClientTransactionHandler {
     pub fn new() -> Self {
         Self {
             transactions: HashMap::new(),
+            dropped_ids: HashSet::new(),
+            unsaved: None,
             clients: HashMap::new(),
+            rules: None,
+            history: HashMap::new(),
+            held_for_review: Vec::new(),
+            risk_policy: RiskPolicy::default(),
+            disputes: DisputeConfig::default(),
+            holds: HashMap::new(),
+            hold_expiries: Schedule::default(),
+            hold_ttl: None,
+            clearing: Schedule::default(),
+            clearing_delay: None,
+            now: None,
+            ledger: Ledger::default(),
+            sequence: 0,
+            statements: None,
+            events: None,
+            observers: Vec::new(),
+            validators: ValidationPipeline::default(),
+            metrics: None,
+            metrics_endpoint: None,
         }
     }
 
-    /// Adds a transaction to the internal transaction map.
-    fn log_transaction(&mut self, t: Transaction) -> Result<(), TransactionError> {
-        if let std::collections::hash_map::Entry::Vacant(e) = self.transactions.entry(t.id()) {
-            e.insert(t);
-            Ok(())
-        } else {
-            Err(TransactionError::TransactionExistsAlready)
-        }
+    /// Emits an account event for every following transaction that is applied.
+    pub fn set_event_stream(&mut self, events: EventStream) {
+        self.events = Some(events);
+        self.ledger.track_changes();
+    }
+
+    /// Collects metrics about every following transaction.
+    pub fn enable_metrics(&mut self) {
+        self.metrics.get_or_insert_with(Metrics::default);
+    }
+
+    /// Collects metrics and publishes them to `endpoint` every
+    /// `METRICS_PUBLISH_INTERVAL` transactions.
+    pub fn serve_metrics(&mut self, endpoint: MetricsEndpoint) {
+        self.enable_metrics();
+        self.metrics_endpoint = Some(endpoint);
+    }
+
+    /// Runs `validator` on every following transaction, after the built-in validators.
+    pub fn add_validator(&mut self, validator: Box<dyn Validator>) {
+        self.validators.push(validator);
+    }
+
+    /// Notifies `observer` about every following transaction and its effects.
+    pub fn add_observer(&mut self, observer: Box<dyn TransactionObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Keeps a statement line for every following transaction, accepted or not.
+    pub fn enable_statements(&mut self) {
+        self.statements.get_or_insert_with(HashMap::new);
+    }
+
+    /// Keeps the ledger's journal for every following transaction, so their
+    /// balances can be queried with `balances_as_of`.
+    pub fn enable_history(&mut self) {
+        self.ledger.keep_journal();
+    }
+
+    /// Authorization holds expire `ttl` seconds after the `authorize` transaction.
+    /// Without a ttl, or without timestamps in the input, holds never expire.
+    pub fn set_hold_ttl(&mut self, ttl: u64) {
+        self.hold_ttl = Some(ttl);
+    }
+
+    /// Replaces the default policy of locking a client on its first chargeback.
+    pub fn set_risk_policy(&mut self, policy: RiskPolicy) {
+        self.risk_policy = policy;
+    }
+
+    /// Decides which transactions can be disputed and for how long.
+    pub fn set_dispute_config(&mut self, disputes: DisputeConfig) {
+        self.disputes = disputes;
+    }
+
+    /// Pending deposits settle automatically `delay` seconds after they arrived.
+    /// Without a delay, or without timestamps in the input, they wait for a `settle` row.
+    pub fn set_clearing_delay(&mut self, delay: u64) {
+        self.clearing_delay = Some(delay);
+    }
+
+    /// Runs `rules` before every following transaction.
+    pub fn set_rules(&mut self, rules: RulesEngine) {
+        self.rules = Some(rules);
+    }
+
+    /// Transactions the rules decided to hold instead of applying them.
+    pub fn held_for_review(&self) -> &[Transaction] {
+        &self.held_for_review
+    }
+
+    /// Adds a transaction to the internal transaction map. `UniqueId` rejected
+    /// rows with a used id before they changed anything, so the id is new.
+    fn log_transaction(&mut self, t: &Transaction, tx_type: TxType, amount: Amount) {
+        let tx_id = t.id();
+        debug_assert!(
+            !self.transactions.contains_key(&tx_id) && !self.dropped_ids.contains(&tx_id)
+        );
+        self.transactions
+            .insert(tx_id, StoredTransaction::new(t, tx_type, amount));
+        mark_unsaved(&mut self.unsaved, tx_id);
     }
 
-    /// Parses the transaction type and reacts appropriately.
+    /// Processes the transaction, records it in the client's statement,
+    /// emits an account event if it was applied and notifies the observers.
     pub fn add_transaction(&mut self, t: Transaction) -> Result<(), TransactionError> {
+        self.sequence += 1;
+        if self.statements.is_none()
+            && self.events.is_none()
+            && self.observers.is_empty()
+            && self.metrics.is_none()
+        {
+            return self.process_transaction(t);
+        }
+
+        let (client_id, row) = (t.client_id(), t.clone());
+        let was_locked = self.clients.get(&client_id).is_some_and(Client::locked);
+        let started = Instant::now();
+        let result = self.process_transaction(t);
+        self.record_metrics(&row, &result, started.elapsed());
+        self.notify_observers(&row, &result, was_locked);
+        let mut changes = self.ledger.take_changes();
+        if result.is_ok() {
+            // an applied row is emitted even if it didn't change any balance
+            if !changes.iter().any(|(id, _)| *id == client_id) {
+                if let Some(client) = self.clients.get(&client_id) {
+                    changes.push((client_id, client.balances()));
+                }
+            }
+            self.emit_events(row.id(), row.raw_tx_type(), changes);
+        }
+        let client = match self.clients.get(&client_id) {
+            Some(client) => client,
+            None => return result,
+        };
+        if let Some(statements) = self.statements.as_mut() {
+            let line = StatementLine::new(self.sequence, &row, &result, client);
+            statements.entry(client_id).or_default().push(line);
+        }
+        result
+    }
+
+    /// Emits an account event for every client in `changes`, which holds their
+    /// balances from before the operation.
+    fn emit_events(&mut self, tx: u32, operation: &str, changes: Vec<(u16, ClientBalances)>) {
+        let events = match self.events.as_mut() {
+            Some(events) => events,
+            None => return,
+        };
+        for (client_id, before) in changes {
+            let client = match self.clients.get(&client_id) {
+                Some(client) => client,
+                None => continue,
+            };
+            let event = AccountEvent {
+                sequence: self.sequence,
+                tx,
+                client: client_id,
+                operation: operation.to_string(),
+                before,
+                after: client.balances(),
+                locked: client.locked(),
+            };
+            if let Err(err) = events.emit(&event) {
+                log::error!("could not emit account event: {}", err);
+            }
+        }
+    }
+
+    fn record_metrics(
+        &mut self,
+        row: &Transaction,
+        result: &Result<(), TransactionError>,
+        latency: Duration,
+    ) {
+        let metrics = match self.metrics.as_mut() {
+            Some(metrics) => metrics,
+            None => return,
+        };
+        // unknown types are counted together, so garbage input can't add labels
+        let tx_type = match row.tx_type() {
+            Ok(_) => row.raw_tx_type(),
+            Err(_) => "unknown",
+        };
+        metrics.record(
+            tx_type,
+            result.as_ref().err().map(TransactionError::code),
+            latency,
+        );
+        match (row.tx_type(), result) {
+            (Ok(TxType::Dispute), Ok(())) => metrics.dispute_opened(),
+            (Ok(TxType::Resolve | TxType::Chargeback), Ok(())) => metrics.dispute_closed(),
+            _ => {}
+        }
+        if self.sequence.is_multiple_of(METRICS_PUBLISH_INTERVAL) {
+            self.publish_metrics();
+        }
+    }
+
+    /// The collected metrics in the Prometheus text format, if metrics are enabled.
+    pub fn render_metrics(&self) -> Option<String> {
+        let metrics = self.metrics.as_ref()?;
+        let gauges = Gauges {
+            open_disputes: metrics.open_disputes(),
+            locked_accounts: self.clients.values().filter(|c| c.locked()).count(),
+            clients: self.clients.len(),
+            transactions: self.transactions.len(),
+        };
+        Some(metrics.render(&gauges))
+    }
+
+    /// Updates the metrics endpoint, if there is one, with the current metrics.
+    pub fn publish_metrics(&self) {
+        if let (Some(endpoint), Some(page)) = (&self.metrics_endpoint, self.render_metrics()) {
+            endpoint.publish(page);
+        }
+    }
+
+    fn notify_observers(
+        &mut self,
+        row: &Transaction,
+        result: &Result<(), TransactionError>,
+        was_locked: bool,
+    ) {
+        if self.observers.is_empty() {
+            return;
+        }
+        let client_id = row.client_id();
+        let is_locked = self.clients.get(&client_id).is_some_and(Client::locked);
+        for observer in self.observers.iter_mut() {
+            match result {
+                Ok(()) => {
+                    observer.transaction_accepted(row);
+                    match row.tx_type() {
+                        Ok(TxType::Dispute) => observer.dispute_opened(client_id, row.id()),
+                        Ok(TxType::Resolve) => observer.dispute_resolved(client_id, row.id()),
+                        Ok(TxType::Chargeback) => observer.charged_back(client_id, row.id()),
+                        _ => {}
+                    }
+                }
+                Err(err) => observer.transaction_rejected(row, err),
+            }
+            if is_locked && !was_locked {
+                observer.account_locked(client_id);
+            }
+        }
+    }
+
+    /// Validates the transaction and checks it against the rules, if any,
+    /// then parses the transaction type and reacts appropriately.
+    fn process_transaction(&mut self, t: Transaction) -> Result<(), TransactionError> {
+        let client_id = t.client_id();
+        let result = self.validate_and_apply(t);
+        // the client's balances are a cache of its ledger accounts, see `Client::apply`
+        debug_assert!(
+            self.clients.get(&client_id).is_none_or(|client| {
+                client.balances() == self.ledger.client_balances(client_id)
+            }),
+            "the balances of client {} don't match its ledger accounts",
+            client_id
+        );
+        result
+    }
+
+    fn validate_and_apply(&mut self, t: Transaction) -> Result<(), TransactionError> {
+        let context =
+            ValidationContext::new(&self.transactions).with_dropped_ids(&self.dropped_ids);
+        self.validators.validate(&t, &context)?;
+
         // Create client if it does not exist yet
 
         self.clients
             .entry(t.client_id())
             .or_insert_with(|| Client::from_id(t.client_id()));
 
-        let client = self
-            .clients
-            .get_mut(&t.client_id())
-            .ok_or(TransactionError::ClientDoesNotExist)?;
+        self.ledger.set_clock(self.sequence, self.now);
+        if let Some(timestamp) = t.timestamp() {
+            self.advance_clock(timestamp);
+        }
+
+        if self.rules.is_none() {
+            return self.apply_transaction(t);
+        }
+
+        self.check_rules(&t)?;
+        self.apply_transaction(t.clone())?;
+        let history = self.history.entry(t.client_id()).or_default();
+        if history.len() == RULES_HISTORY_LEN {
+            history.pop_front();
+        }
+        history.push_back(t);
+        Ok(())
+    }
+
+    /// Evaluates the rules for `t` and acts on any decision other than accept.
+    fn check_rules(&mut self, t: &Transaction) -> Result<(), TransactionError> {
+        let rules = match &self.rules {
+            Some(rules) => rules,
+            None => return Ok(()),
+        };
+        let client =
+            self.clients
+                .get_mut(&t.client_id())
+                .ok_or(TransactionError::ClientDoesNotExist {
+                    tx_id: t.id(),
+                    client_id: t.client_id(),
+                })?;
+        let history = self.history.entry(t.client_id()).or_default();
 
-        match t.tx_type()? {
+        match rules.evaluate(t, client, history.make_contiguous())? {
+            RuleDecision::Accept => Ok(()),
+            RuleDecision::Reject(reason) => Err(TransactionError::RejectedByRules {
+                tx_id: t.id(),
+                client_id: t.client_id(),
+                reason,
+            }),
+            RuleDecision::Hold => {
+                self.held_for_review.push(t.clone());
+                Err(TransactionError::HeldByRules {
+                    tx_id: t.id(),
+                    client_id: t.client_id(),
+                })
+            }
+            RuleDecision::Lock => {
+                client.lock();
+                Err(TransactionError::LockedByRules {
+                    client_id: t.client_id(),
+                    tx_id: t.id(),
+                })
+            }
+        }
+    }
+
+    fn apply_transaction(&mut self, t: Transaction) -> Result<(), TransactionError> {
+        let client =
+            self.clients
+                .get_mut(&t.client_id())
+                .ok_or(TransactionError::ClientDoesNotExist {
+                    tx_id: t.id(),
+                    client_id: t.client_id(),
+                })?;
+
+        let tx_type = t.tx_type()?;
+        match tx_type {
             TxType::Deposit => {
-                let amount = t.amount().unwrap();
-                client.deposit(amount)?;
-                self.log_transaction(t)?;
+                let amount =
+                    t.amount()
+                        .map(Amount::from_f64)
+                        .ok_or(TransactionError::MissingAmount {
+                            tx_id: t.id(),
+                            client_id: t.client_id(),
+                        })?;
+                let transfer = client.deposit(t.id(), amount)?;
+                self.ledger.record(t.id(), transfer);
+                self.log_transaction(&t, tx_type, amount);
                 Ok(())
             }
             TxType::Withdrawal => {
-                let amount = t.amount().unwrap();
-                client.withdraw(amount)?;
-                self.log_transaction(t)?;
+                let amount =
+                    t.amount()
+                        .map(Amount::from_f64)
+                        .ok_or(TransactionError::MissingAmount {
+                            tx_id: t.id(),
+                            client_id: t.client_id(),
+                        })?;
+                let transfer = client.withdraw(t.id(), amount)?;
+                self.ledger.record(t.id(), transfer);
+                self.log_transaction(&t, tx_type, amount);
                 Ok(())
             }
             TxType::Dispute => {
-                self.dispute_transaction(t.id())?;
+                self.dispute_transaction(t.id(), t.client_id())?;
                 Ok(())
             }
             TxType::Resolve => {
-                self.resolve_transaction(t.id())?;
+                self.resolve_transaction(t.id(), t.client_id())?;
                 Ok(())
             }
             TxType::Chargeback => {
-                self.chargeback_transaction(t.id())?;
+                self.chargeback_transaction(t.id(), t.client_id())?;
+                Ok(())
+            }
+            TxType::Authorize => {
+                let amount =
+                    t.amount()
+                        .map(Amount::from_f64)
+                        .ok_or(TransactionError::MissingAmount {
+                            tx_id: t.id(),
+                            client_id: t.client_id(),
+                        })?;
+                let transfer = client.authorize(t.id(), amount)?;
+                self.ledger.record(t.id(), transfer);
+                let expires_at = self.now.zip(self.hold_ttl).map(|(now, ttl)| now + ttl);
+                if let Some(at) = expires_at {
+                    self.hold_expiries.push(at, t.id());
+                }
+                self.holds.insert(
+                    t.id(),
+                    AuthorizationHold::new(t.client_id(), amount, expires_at),
+                );
+                self.log_transaction(&t, tx_type, amount);
+                Ok(())
+            }
+            TxType::Capture => {
+                self.capture_hold(t.id(), t.client_id())?;
+                Ok(())
+            }
+            TxType::Void => {
+                self.void_hold(t.id(), t.client_id())?;
+                Ok(())
+            }
+            TxType::PendingDeposit => {
+                let amount =
+                    t.amount()
+                        .map(Amount::from_f64)
+                        .ok_or(TransactionError::MissingAmount {
+                            tx_id: t.id(),
+                            client_id: t.client_id(),
+                        })?;
+                let transfer = client.deposit_pending(t.id(), amount)?;
+                self.ledger.record(t.id(), transfer);
+                if let Some((now, delay)) = self.now.zip(self.clearing_delay) {
+                    self.clearing.push(now + delay, t.id());
+                }
+                self.log_transaction(&t, tx_type, amount);
+                Ok(())
+            }
+            TxType::Settle => {
+                self.settle_transaction(t.id(), t.client_id())?;
+                Ok(())
+            }
+            TxType::Return => {
+                self.return_transaction(t.id(), t.client_id())?;
+                Ok(())
+            }
+            TxType::Reversal => {
+                self.reverse_transaction(t.id(), t.client_id())?;
+                Ok(())
+            }
+            TxType::Refund => {
+                let amount =
+                    t.amount()
+                        .map(Amount::from_f64)
+                        .ok_or(TransactionError::MissingAmount {
+                            tx_id: t.id(),
+                            client_id: t.client_id(),
+                        })?;
+                self.refund_transaction(t.id(), t.client_id(), amount)?;
                 Ok(())
             }
         }
     }
 
-    fn dispute_transaction(&mut self, id: u32) -> Result<(), TransactionError> {
-        let tx = self
-            .transactions
-            .get_mut(&id)
-            .ok_or(TransactionError::InvalidDispute)?;
+    /// Undoes what is left of a deposit or withdrawal.
+    fn reverse_transaction(&mut self, id: u32, client_id: u16) -> Result<(), TransactionError> {
+        let tx = referenced_transaction(&mut self.transactions, &mut self.unsaved, id, client_id)
+            .map_err(|state| TransactionError::InvalidReversal {
+            tx_id: id,
+            client_id,
+            state,
+        })?;
 
-        let client = self
-            .clients
-            .get_mut(&tx.client_id())
-            .ok_or(TransactionError::InvalidDispute)?;
+        let client =
+            self.clients
+                .get_mut(&client_id)
+                .ok_or(TransactionError::ClientDoesNotExist {
+                    tx_id: id,
+                    client_id,
+                })?;
+
+        let amount = tx.reversal_amount()?;
+        let transfer = if tx.tx_type() == TxType::Withdrawal {
+            client.refund(id, amount)?
+        } else {
+            client.reverse_deposit(id, amount)?
+        };
+        self.ledger.record(id, transfer);
+        tx.reverse();
+        Ok(())
+    }
+
+    /// Returns `amount` of a withdrawal to the client.
+    fn refund_transaction(
+        &mut self,
+        id: u32,
+        client_id: u16,
+        amount: Amount,
+    ) -> Result<(), TransactionError> {
+        let tx = referenced_transaction(&mut self.transactions, &mut self.unsaved, id, client_id)
+            .map_err(|state| TransactionError::InvalidRefund {
+            tx_id: id,
+            client_id,
+            state,
+        })?;
+
+        let client =
+            self.clients
+                .get_mut(&client_id)
+                .ok_or(TransactionError::ClientDoesNotExist {
+                    tx_id: id,
+                    client_id,
+                })?;
+
+        tx.check_refund(amount)?;
+        let transfer = client.refund(id, amount)?;
+        self.ledger.record(id, transfer);
+        tx.refund(amount);
+        Ok(())
+    }
 
-        tx.dispute()?;
+    /// Moves the engine's clock forward, expires all holds and clears all pending
+    /// deposits that are due. Their account events come before the row's.
+    fn advance_clock(&mut self, timestamp: u64) {
+        let now = self.now.map_or(timestamp, |now| now.max(timestamp));
+        self.now = Some(now);
+        self.ledger.set_clock(self.sequence, self.now);
+        for id in self.hold_expiries.pop_due(now) {
+            if let Some(hold) = self.holds.remove(&id) {
+                log::info!("authorization hold {} expired", id);
+                let client_id = hold.client_id();
+                if let Some(client) = self.clients.get_mut(&client_id) {
+                    match client.void(id, hold.remaining()) {
+                        Ok(transfer) => self.ledger.record(id, transfer),
+                        Err(err) => log::error!(
+                            tx = id, client = client_id, tx_type = TxType::Void.as_str(), code = err.code();
+                            "{}", err
+                        ),
+                    }
+                }
+                let changes = self.ledger.take_changes();
+                self.emit_events(id, TxType::Void.as_str(), changes);
+            }
+        }
+        for id in self.clearing.pop_due(now) {
+            let client_id = match self.transactions.get(&id) {
+                Some(tx) if tx.pending() => tx.client_id(),
+                _ => continue,
+            };
+            log::info!("pending deposit {} cleared", id);
+            if let Err(err) = self.settle_transaction(id, client_id) {
+                log::error!(
+                    tx = id, client = client_id, tx_type = TxType::Settle.as_str(), code = err.code();
+                    "{}", err
+                );
+            }
+            let changes = self.ledger.take_changes();
+            self.emit_events(id, TxType::Settle.as_str(), changes);
+        }
+    }
+
+    /// Credits a pending deposit to the client's available funds.
+    fn settle_transaction(&mut self, id: u32, client_id: u16) -> Result<(), TransactionError> {
+        let tx = referenced_transaction(&mut self.transactions, &mut self.unsaved, id, client_id)
+            .map_err(|state| TransactionError::InvalidSettle {
+            tx_id: id,
+            client_id,
+            state,
+        })?;
 
-        client.dispute(
-            tx.amount()
-                .ok_or(TransactionError::InvalidTransactionRecord)? as f64,
-        )?;
+        let client =
+            self.clients
+                .get_mut(&client_id)
+                .ok_or(TransactionError::ClientDoesNotExist {
+                    tx_id: id,
+                    client_id,
+                })?;
+        // the transaction is only marked once the funds could be moved
+        let mut settled = *tx;
+        settled.settle()?;
+
+        let transfer = client.settle(id, settled.amount())?;
+        *tx = settled;
+        self.ledger.record(id, transfer);
         Ok(())
     }
 
-    fn resolve_transaction(&mut self, id: u32) -> Result<(), TransactionError> {
-        let tx = self
-            .transactions
-            .get_mut(&id)
-            .ok_or(TransactionError::InvalidResolve)?;
+    /// Reverses a pending deposit before it was settled.
+    fn return_transaction(&mut self, id: u32, client_id: u16) -> Result<(), TransactionError> {
+        let tx = referenced_transaction(&mut self.transactions, &mut self.unsaved, id, client_id)
+            .map_err(|state| TransactionError::InvalidReturn {
+            tx_id: id,
+            client_id,
+            state,
+        })?;
 
-        let client = self
-            .clients
-            .get_mut(&tx.client_id())
-            .ok_or(TransactionError::InvalidResolve)?;
-        tx.resolve()?;
-
-        client.resolve(
-            tx.amount()
-                .ok_or(TransactionError::InvalidTransactionRecord)? as f64,
-        )?;
+        let client =
+            self.clients
+                .get_mut(&client_id)
+                .ok_or(TransactionError::ClientDoesNotExist {
+                    tx_id: id,
+                    client_id,
+                })?;
+        let mut returned = *tx;
+        returned.return_deposit()?;
+
+        let transfer = client.return_pending(id, returned.amount())?;
+        *tx = returned;
+        self.ledger.record(id, transfer);
+        Ok(())
+    }
+
+    /// Settles the remaining amount of the hold as a withdrawal.
+    fn capture_hold(&mut self, id: u32, client_id: u16) -> Result<(), TransactionError> {
+        let hold = match self.holds.get(&id) {
+            Some(hold) if hold.client_id() == client_id => hold,
+            found => {
+                return Err(TransactionError::InvalidCapture {
+                    tx_id: id,
+                    client_id,
+                    state: missing_or_other_client(found.is_some()),
+                })
+            }
+        };
+
+        let client =
+            self.clients
+                .get_mut(&client_id)
+                .ok_or(TransactionError::ClientDoesNotExist {
+                    tx_id: id,
+                    client_id,
+                })?;
+
+        let transfer = client.capture(id, hold.remaining())?;
+        self.ledger.record(id, transfer);
+        self.holds.remove(&id);
         Ok(())
     }
 
-    fn chargeback_transaction(&mut self, id: u32) -> Result<(), TransactionError> {
-        let tx = self
-            .transactions
-            .get_mut(&id)
-            .ok_or(TransactionError::InvalidResolve)?;
+    /// Releases the remaining amount of the hold.
+    fn void_hold(&mut self, id: u32, client_id: u16) -> Result<(), TransactionError> {
+        let client =
+            self.clients
+                .get_mut(&client_id)
+                .ok_or(TransactionError::ClientDoesNotExist {
+                    tx_id: id,
+                    client_id,
+                })?;
 
-        let client = self
+        match self.holds.get(&id) {
+            Some(hold) if hold.client_id() == client_id => {
+                let transfer = client.void(id, hold.remaining())?;
+                self.ledger.record(id, transfer);
+                self.holds.remove(&id);
+                Ok(())
+            }
+            found => Err(TransactionError::InvalidVoid {
+                tx_id: id,
+                client_id,
+                state: missing_or_other_client(found.is_some()),
+            }),
+        }
+    }
+
+    fn dispute_transaction(&mut self, id: u32, client_id: u16) -> Result<(), TransactionError> {
+        let tx = referenced_transaction(&mut self.transactions, &mut self.unsaved, id, client_id)
+            .map_err(|state| TransactionError::InvalidDispute {
+            tx_id: id,
+            client_id,
+            state,
+        })?;
+
+        let expired = self
+            .disputes
+            .window
+            .zip(tx.timestamp().zip(self.now))
+            .is_some_and(|(window, (at, now))| now.saturating_sub(at) > window);
+        if expired {
+            return Err(TransactionError::InvalidDispute {
+                tx_id: id,
+                client_id,
+                state: TxState::Expired,
+            });
+        }
+
+        let client =
+            self.clients
+                .get_mut(&client_id)
+                .ok_or(TransactionError::ClientDoesNotExist {
+                    tx_id: id,
+                    client_id,
+                })?;
+
+        // the transaction is only marked once the client could hold its amount
+        let mut disputed = *tx;
+        disputed.dispute(self.disputes.withdrawals)?;
+
+        let amount = disputed.remaining_amount();
+        let transfer = if disputed.tx_type() == TxType::Withdrawal {
+            client.dispute_withdrawal(id, amount, &self.risk_policy)?
+        } else {
+            client.dispute(id, amount, &self.risk_policy)?
+        };
+        *tx = disputed;
+        self.ledger.record(id, transfer);
+        Ok(())
+    }
+
+    fn resolve_transaction(&mut self, id: u32, client_id: u16) -> Result<(), TransactionError> {
+        let tx = referenced_transaction(&mut self.transactions, &mut self.unsaved, id, client_id)
+            .map_err(|state| TransactionError::InvalidResolve {
+            tx_id: id,
+            client_id,
+            state,
+        })?;
+
+        let client =
+            self.clients
+                .get_mut(&client_id)
+                .ok_or(TransactionError::ClientDoesNotExist {
+                    tx_id: id,
+                    client_id,
+                })?;
+        let mut settled = *tx;
+        settled.resolve()?;
+
+        let amount = settled.remaining_amount();
+        let transfer = if settled.tx_type() == TxType::Withdrawal {
+            client.resolve_withdrawal(id, amount)?
+        } else {
+            client.resolve(id, amount)?
+        };
+        *tx = settled;
+        self.ledger.record(id, transfer);
+        Ok(())
+    }
+
+    fn chargeback_transaction(&mut self, id: u32, client_id: u16) -> Result<(), TransactionError> {
+        let tx = referenced_transaction(&mut self.transactions, &mut self.unsaved, id, client_id)
+            .map_err(|state| TransactionError::InvalidChargeback {
+            tx_id: id,
+            client_id,
+            state,
+        })?;
+
+        let client =
+            self.clients
+                .get_mut(&client_id)
+                .ok_or(TransactionError::ClientDoesNotExist {
+                    tx_id: id,
+                    client_id,
+                })?;
+        let mut settled = *tx;
+        settled.chargeback()?;
+
+        let amount = settled.remaining_amount();
+        let transfer = if settled.tx_type() == TxType::Withdrawal {
+            client.chargeback_withdrawal(id, amount)?
+        } else {
+            client.chargeback(id, amount, &self.risk_policy)?
+        };
+        *tx = settled;
+        self.ledger.record(id, transfer);
+        if !self.disputes.keep_charged_back {
+            self.transactions.remove(&id);
+            self.dropped_ids.insert(id);
+            mark_unsaved(&mut self.unsaved, id);
+        }
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    /// Writes the clients, transactions, holds and the ledger to `out`, in CBOR.
+    pub fn save_state<W: Write>(&self, out: W) -> Result<(), EngineError> {
+        let state = self.saved_state(&self.transactions);
+        ciborium::into_writer(&state, out).map_err(|err| EngineError::Checkpoint(err.to_string()))
+    }
+
+    /// Tracks the transactions that change from now on, for `save_changes`.
+    /// Called again, it forgets the changes so far.
+    pub fn track_unsaved_changes(&mut self) {
+        self.unsaved = Some(HashSet::new());
+    }
+
+    /// Writes what `save_state` does, but only the transactions that changed since
+    /// `track_unsaved_changes` or the last call, so saving doesn't take longer the
+    /// more transactions there are. `restore_changes` applies it to the state before.
+    pub fn save_changes<W: Write>(&mut self, out: W) -> Result<(), EngineError> {
+        let unsaved = self.unsaved.replace(HashSet::new()).ok_or_else(|| {
+            EngineError::Checkpoint("changes of the transactions are not tracked".to_string())
+        })?;
+        let changed: Vec<_> = unsaved
+            .into_iter()
+            .map(|id| (id, self.transactions.get(&id)))
+            .collect();
+        let state = self.saved_state(changed);
+        ciborium::into_writer(&state, out).map_err(|err| EngineError::Checkpoint(err.to_string()))
+    }
+
+    fn saved_state<T>(&self, transactions: T) -> SavedState<'_, T> {
+        SavedState {
+            clients: self.clients.values().map(ClientState::from).collect(),
+            transactions,
+            dropped_ids: &self.dropped_ids,
+            history: &self.history,
+            held_for_review: &self.held_for_review,
+            holds: &self.holds,
+            hold_expiries: &self.hold_expiries,
+            clearing: &self.clearing,
+            now: self.now,
+            ledger: &self.ledger,
+            sequence: self.sequence,
+        }
+    }
+
+    /// Replaces the state with one written by `save_state`, the configuration stays.
+    pub fn restore_state<R: Read>(&mut self, input: R) -> Result<(), EngineError> {
+        let state: RestoredState<HashMap<u32, StoredTransaction>> =
+            ciborium::from_reader(input).map_err(|err| EngineError::Checkpoint(err.to_string()))?;
+        self.transactions = self.restore(state);
+        Ok(())
+    }
+
+    /// Applies changes written by `save_changes` to the restored state.
+    pub fn restore_changes<R: Read>(&mut self, input: R) -> Result<(), EngineError> {
+        let state: RestoredState<Vec<(u32, Option<StoredTransaction>)>> =
+            ciborium::from_reader(input).map_err(|err| EngineError::Checkpoint(err.to_string()))?;
+        for (id, tx) in self.restore(state) {
+            match tx {
+                Some(tx) => self.transactions.insert(id, tx),
+                None => self.transactions.remove(&id),
+            };
+        }
+        Ok(())
+    }
+
+    /// Restores everything but the transactions, which are returned.
+    fn restore<T>(&mut self, state: RestoredState<T>) -> T {
+        self.clients = state
             .clients
-            .get_mut(&tx.client_id())
-            .ok_or(TransactionError::InvalidResolve)?;
-        tx.resolve()?;
-        tx.chargeback()?;
-
-        client.chargeback(
-            tx.amount()
-                .ok_or(TransactionError::InvalidTransactionRecord)? as f64,
-        )?;
+            .into_iter()
+            .map(|client| {
+                let client = Client::from(client);
+                (client.id(), client)
+            })
+            .collect();
+        self.dropped_ids = state.dropped_ids;
+        self.history = state.history;
+        self.held_for_review = state.held_for_review;
+        self.holds = state.holds;
+        self.hold_expiries = state.hold_expiries;
+        self.clearing = state.clearing;
+        self.now = state.now;
+        self.ledger = state.ledger;
+        if self.events.is_some() {
+            self.ledger.track_changes();
+        }
+        self.sequence = state.sequence;
+        state.transactions
+    }
+
+    /// Confirms that the ledger sums to zero and that every client's
+    /// balances match the postings on its accounts.
+    pub fn check_trial_balance(&self) -> Result<(), EngineError> {
+        let sum = self.ledger.trial_balance();
+        if sum != Amount::ZERO {
+            return Err(EngineError::UnbalancedLedger(sum));
+        }
+        for client in self.clients.values() {
+            if client.balances() != self.ledger.client_balances(client.id()) {
+                return Err(EngineError::LedgerMismatch(client.id()));
+            }
+        }
         Ok(())
     }
 
+    /// The number of transactions added so far.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// A client's balances after all rows up to and including `point`.
+    /// Timestamps refer to the latest timestamp seen up to a row, so rows
+    /// without a timestamp belong to the one before them. Zero unless history
+    /// was enabled before the transactions were added.
+    pub fn balances_as_of(&self, client_id: u16, point: PointInTime) -> ClientBalances {
+        self.ledger.client_balances_as_of(client_id, point)
+    }
+
+    /// The statement lines of a client that match `filter`, in input order.
+    /// Empty unless statements were enabled before the transactions were added.
+    pub fn statement<'a>(
+        &'a self,
+        client_id: u16,
+        filter: &'a StatementFilter,
+    ) -> impl Iterator<Item = &'a StatementLine> + 'a {
+        self.statements
+            .as_ref()
+            .and_then(|statements| statements.get(&client_id))
+            .into_iter()
+            .flatten()
+            .filter(move |line| filter.matches(line))
+    }
+
+    /// Dispute and chargeback counters of every client.
+    pub fn risk_report(&self) -> impl Iterator<Item = RiskReportRow> + '_ {
+        self.clients.values().map(Client::risk_report)
+    }
+
+    /// Authorization holds that were neither captured, voided nor expired yet.
+    #[allow(dead_code)]
+    pub fn holds(&self) -> &HashMap<u32, AuthorizationHold> {
+        &self.holds
+    }
+
     pub fn clients(&self) -> &HashMap<u16, Client> {
         // TODO: maybe use iterator over clients as return value instead
         &self.clients
     }
 }
 
+/// Looks up the transaction a row refers to, it has to belong to the row's client.
+fn referenced_transaction<'a>(
+    transactions: &'a mut HashMap<u32, StoredTransaction>,
+    unsaved: &mut Option<HashSet<u32>>,
+    id: u32,
+    client_id: u16,
+) -> Result<&'a mut StoredTransaction, TxState> {
+    match transactions.get_mut(&id) {
+        Some(tx) if tx.client_id() == client_id => {
+            // the caller may change it
+            mark_unsaved(unsaved, id);
+            Ok(tx)
+        }
+        found => Err(missing_or_other_client(found.is_some())),
+    }
+}
+
+fn mark_unsaved(unsaved: &mut Option<HashSet<u32>>, id: u32) {
+    if let Some(unsaved) = unsaved {
+        unsaved.insert(id);
+    }
+}
+
+fn missing_or_other_client(found: bool) -> TxState {
+    if found {
+        TxState::OtherClient
+    } else {
+        TxState::NotFound
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::ClientTransactionHandler;
-    use crate::transaction::{Transaction, TxType};
+    use crate::amount::Amount;
+    use crate::config::DisputeConfig;
+    use crate::errors::{TransactionError, TxState};
+    use crate::events::EventStream;
+    use crate::ledger::{Account, PointInTime};
+    use crate::observer::{spawn_observer, TransactionObserver};
+    use crate::risk::RiskPolicy;
+    use crate::rules::RulesEngine;
+    use crate::statement::StatementFilter;
+    use crate::transaction::{StoredTransaction, Transaction, TxType};
+    use crate::validation::{ValidationContext, Validator};
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    /// Collects everything written to it, so tests can inspect the event stream.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
 
     #[test]
     fn it_adds_transactions_to_the_log() {
@@ -148,7 +1100,14 @@ mod tests {
         assert!(!handler.transactions.contains_key(&tx_id));
         handler.add_transaction(t.clone()).unwrap();
         assert!(handler.transactions.contains_key(&2));
-        assert_eq!(handler.transactions.get(&2).unwrap(), &t);
+        assert_eq!(
+            handler.transactions.get(&2).unwrap(),
+            &StoredTransaction::new(&t, TxType::Deposit, Amount::from_f64(1.0))
+        );
+
+        let t = Transaction::new("dispute", 1, tx_id, None);
+        handler.add_transaction(t).unwrap();
+        assert_eq!(handler.transactions.len(), 1);
     }
 
     #[test]
@@ -169,7 +1128,10 @@ mod tests {
         let tx_type = (TxType::Deposit).to_string().to_ascii_lowercase();
         let t = Transaction::new(tx_type, client_id, 2, Some(1.0));
         handler.add_transaction(t).unwrap();
-        assert_eq!(handler.clients().get(&client_id).unwrap().total(), 1.0);
+        assert_eq!(
+            handler.clients().get(&client_id).unwrap().total(),
+            Amount::from_f64(1.0)
+        );
     }
 
     #[test]
@@ -186,7 +1148,10 @@ mod tests {
         let t = Transaction::new(tx_type, client_id, 3, Some(1.0));
         handler.add_transaction(t).unwrap();
 
-        assert_eq!(handler.clients().get(&client_id).unwrap().total(), 0.0);
+        assert_eq!(
+            handler.clients().get(&client_id).unwrap().total(),
+            Amount::ZERO
+        );
     }
 
     #[test]
@@ -204,7 +1169,10 @@ mod tests {
         assert!(handler.add_transaction(t).is_err());
 
         // the client balance is unchanged afterwards
-        assert_eq!(handler.clients().get(&client_id).unwrap().total(), 1.0);
+        assert_eq!(
+            handler.clients().get(&client_id).unwrap().total(),
+            Amount::from_f64(1.0)
+        );
     }
 
     #[test]
@@ -222,9 +1190,18 @@ mod tests {
         handler.add_transaction(t).unwrap();
 
         assert!(handler.transactions.get(&tx_id).unwrap().disputed());
-        assert_eq!(handler.clients().get(&client_id).unwrap().total(), 1.0);
-        assert_eq!(handler.clients().get(&client_id).unwrap().held(), 1.0);
-        assert_eq!(handler.clients().get(&client_id).unwrap().available(), 0.0);
+        assert_eq!(
+            handler.clients().get(&client_id).unwrap().total(),
+            Amount::from_f64(1.0)
+        );
+        assert_eq!(
+            handler.clients().get(&client_id).unwrap().held(),
+            Amount::from_f64(1.0)
+        );
+        assert_eq!(
+            handler.clients().get(&client_id).unwrap().available(),
+            Amount::ZERO
+        );
     }
 
     #[test]
@@ -257,9 +1234,18 @@ mod tests {
         handler.add_transaction(t).unwrap();
 
         assert!(!handler.transactions.get(&tx_id).unwrap().disputed());
-        assert_eq!(handler.clients().get(&client_id).unwrap().total(), 1.0);
-        assert_eq!(handler.clients().get(&client_id).unwrap().held(), 0.0);
-        assert_eq!(handler.clients().get(&client_id).unwrap().available(), 1.0);
+        assert_eq!(
+            handler.clients().get(&client_id).unwrap().total(),
+            Amount::from_f64(1.0)
+        );
+        assert_eq!(
+            handler.clients().get(&client_id).unwrap().held(),
+            Amount::ZERO
+        );
+        assert_eq!(
+            handler.clients().get(&client_id).unwrap().available(),
+            Amount::from_f64(1.0)
+        );
     }
 
     #[test]
@@ -300,8 +1286,972 @@ mod tests {
 
         assert!(!handler.transactions.get(&tx_id).unwrap().disputed());
         assert!(handler.transactions.get(&tx_id).unwrap().charged_back());
-        assert_eq!(handler.clients().get(&client_id).unwrap().total(), 0.0);
-        assert_eq!(handler.clients().get(&client_id).unwrap().held(), 0.0);
-        assert_eq!(handler.clients().get(&client_id).unwrap().available(), 0.0);
+        assert_eq!(
+            handler.clients().get(&client_id).unwrap().total(),
+            Amount::ZERO
+        );
+        assert_eq!(
+            handler.clients().get(&client_id).unwrap().held(),
+            Amount::ZERO
+        );
+        assert_eq!(
+            handler.clients().get(&client_id).unwrap().available(),
+            Amount::ZERO
+        );
+    }
+
+    #[test]
+    fn transactions_held_by_the_rules_are_not_applied() {
+        let mut handler = ClientTransactionHandler::new();
+        let rules =
+            RulesEngine::from_script("if tx.amount > 100.0 { hold() }", Duration::from_millis(50))
+                .unwrap();
+        handler.set_rules(rules);
+
+        let tx_type = (TxType::Deposit).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, 1, 2, Some(1000.0));
+        assert!(handler.add_transaction(t).is_err());
+
+        assert_eq!(handler.held_for_review().len(), 1);
+        assert!(!handler.transactions.contains_key(&2));
+        assert_eq!(handler.clients().get(&1).unwrap().total(), Amount::ZERO);
+    }
+
+    #[test]
+    fn the_rules_can_lock_a_client_based_on_its_history() {
+        let mut handler = ClientTransactionHandler::new();
+        let rules = RulesEngine::from_script(
+            "if history.len() >= 2 { lock() }",
+            Duration::from_millis(50),
+        )
+        .unwrap();
+        handler.set_rules(rules);
+
+        for tx_id in 1..=3 {
+            let tx_type = (TxType::Deposit).to_string().to_ascii_lowercase();
+            let t = Transaction::new(tx_type, 1, tx_id, Some(1.0));
+            let _ = handler.add_transaction(t);
+        }
+
+        assert!(handler.clients().get(&1).unwrap().locked());
+        assert_eq!(
+            handler.clients().get(&1).unwrap().total(),
+            Amount::from_f64(2.0)
+        );
+    }
+
+    #[test]
+    fn the_risk_policy_decides_what_happens_on_a_chargeback() {
+        let mut handler = ClientTransactionHandler::new();
+        handler.set_risk_policy(RiskPolicy::new(vec!["chargebacks>=1:freeze"
+            .parse()
+            .unwrap()]));
+        let client_id = 1;
+        let tx_id = 2;
+        let tx_type = (TxType::Deposit).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, client_id, tx_id, Some(1.0));
+        handler.add_transaction(t).unwrap();
+        let tx_type = (TxType::Deposit).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, client_id, 3, Some(1.0));
+        handler.add_transaction(t).unwrap();
+
+        for tx_type in [TxType::Dispute, TxType::Chargeback] {
+            let tx_type = tx_type.to_string().to_ascii_lowercase();
+            let t = Transaction::new(tx_type, client_id, tx_id, None);
+            handler.add_transaction(t).unwrap();
+        }
+
+        let client = handler.clients().get(&client_id).unwrap();
+        assert!(client.frozen());
+        assert!(!client.locked());
+        assert_eq!(client.risk().chargeback_ratio(), 0.5);
+
+        // frozen clients can still deposit, but not withdraw
+        let tx_type = (TxType::Deposit).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, client_id, 4, Some(1.0));
+        handler.add_transaction(t).unwrap();
+        let tx_type = (TxType::Withdrawal).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, client_id, 5, Some(1.0));
+        assert!(handler.add_transaction(t).is_err());
+        assert_eq!(
+            handler.clients().get(&client_id).unwrap().total(),
+            Amount::from_f64(2.0)
+        );
+    }
+
+    #[test]
+    fn an_authorization_can_be_captured_or_voided() {
+        let mut handler = ClientTransactionHandler::new();
+        let client_id = 1;
+        let tx_type = (TxType::Deposit).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, client_id, 1, Some(10.0));
+        handler.add_transaction(t).unwrap();
+
+        let tx_type = (TxType::Authorize).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, client_id, 2, Some(6.0));
+        handler.add_transaction(t).unwrap();
+        let client = handler.clients().get(&client_id).unwrap();
+        assert_eq!(client.available(), Amount::from_f64(4.0));
+        assert_eq!(client.authorized(), Amount::from_f64(6.0));
+        assert_eq!(client.held(), Amount::ZERO);
+        assert_eq!(client.total(), Amount::from_f64(10.0));
+
+        let tx_type = (TxType::Capture).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, client_id, 2, None);
+        handler.add_transaction(t).unwrap();
+        assert!(handler.holds().is_empty());
+        let client = handler.clients().get(&client_id).unwrap();
+        assert_eq!(client.available(), Amount::from_f64(4.0));
+        assert_eq!(client.authorized(), Amount::ZERO);
+        assert_eq!(client.total(), Amount::from_f64(4.0));
+
+        let tx_type = (TxType::Authorize).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, client_id, 3, Some(3.0));
+        handler.add_transaction(t).unwrap();
+        let tx_type = (TxType::Void).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, client_id, 3, None);
+        handler.add_transaction(t).unwrap();
+        assert!(handler.holds().is_empty());
+
+        let client = handler.clients().get(&client_id).unwrap();
+        assert_eq!(client.available(), Amount::from_f64(4.0));
+        assert_eq!(client.authorized(), Amount::ZERO);
+        assert_eq!(client.total(), Amount::from_f64(4.0));
+    }
+
+    #[test]
+    fn captures_take_no_amount() {
+        let mut handler = ClientTransactionHandler::new();
+        let tx_type = (TxType::Deposit).to_string().to_ascii_lowercase();
+        handler
+            .add_transaction(Transaction::new(tx_type, 1, 1, Some(10.0)))
+            .unwrap();
+        let tx_type = (TxType::Authorize).to_string().to_ascii_lowercase();
+        handler
+            .add_transaction(Transaction::new(tx_type, 1, 2, Some(5.0)))
+            .unwrap();
+
+        let tx_type = (TxType::Capture).to_string().to_ascii_lowercase();
+        assert!(matches!(
+            handler.add_transaction(Transaction::new(tx_type.clone(), 1, 2, Some(5.0))),
+            Err(TransactionError::UnexpectedAmount { .. })
+        ));
+        // holds can only be captured by their own client
+        assert!(handler
+            .add_transaction(Transaction::new(tx_type, 2, 2, None))
+            .is_err());
+        assert_eq!(
+            handler.clients().get(&1).unwrap().authorized(),
+            Amount::from_f64(5.0)
+        );
+    }
+
+    #[test]
+    fn authorization_holds_expire_after_the_ttl() {
+        let mut handler = ClientTransactionHandler::new();
+        handler.set_hold_ttl(60);
+        let tx_type = (TxType::Deposit).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, 1, 1, Some(10.0)).with_timestamp(1000);
+        handler.add_transaction(t).unwrap();
+        let tx_type = (TxType::Authorize).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, 1, 2, Some(5.0)).with_timestamp(1010);
+        handler.add_transaction(t).unwrap();
+
+        let tx_type = (TxType::Deposit).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, 2, 3, Some(1.0)).with_timestamp(1070);
+        handler.add_transaction(t).unwrap();
+
+        assert!(handler.holds().is_empty());
+        assert_eq!(
+            handler.clients().get(&1).unwrap().available(),
+            Amount::from_f64(10.0)
+        );
+        let tx_type = (TxType::Capture).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, 1, 2, None);
+        assert!(handler.add_transaction(t).is_err());
+    }
+
+    #[test]
+    fn pending_deposits_are_not_available_until_they_are_settled() {
+        let mut handler = ClientTransactionHandler::new();
+        let client_id = 1;
+        let tx_type = (TxType::PendingDeposit).to_string();
+        let t = Transaction::new(tx_type, client_id, 1, Some(5.0));
+        handler.add_transaction(t).unwrap();
+        let client = handler.clients().get(&client_id).unwrap();
+        assert_eq!(client.pending(), Amount::from_f64(5.0));
+        assert_eq!(client.total(), Amount::ZERO);
+
+        let tx_type = (TxType::Withdrawal).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, client_id, 2, Some(5.0));
+        assert!(handler.add_transaction(t).is_err());
+        // pending deposits can't be disputed before they are settled
+        let tx_type = (TxType::Dispute).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, client_id, 1, None);
+        assert!(handler.add_transaction(t).is_err());
+
+        let tx_type = (TxType::Settle).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, client_id, 1, None);
+        handler.add_transaction(t).unwrap();
+        let client = handler.clients().get(&client_id).unwrap();
+        assert_eq!(client.pending(), Amount::ZERO);
+        assert_eq!(client.available(), Amount::from_f64(5.0));
+
+        let tx_type = (TxType::Dispute).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, client_id, 1, None);
+        handler.add_transaction(t).unwrap();
+        assert_eq!(
+            handler.clients().get(&client_id).unwrap().held(),
+            Amount::from_f64(5.0)
+        );
+    }
+
+    #[test]
+    fn pending_deposits_can_be_returned_before_they_are_settled() {
+        let mut handler = ClientTransactionHandler::new();
+        let tx_type = (TxType::PendingDeposit).to_string();
+        let t = Transaction::new(tx_type, 1, 1, Some(5.0));
+        handler.add_transaction(t).unwrap();
+
+        let tx_type = (TxType::Return).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, 1, 1, None);
+        handler.add_transaction(t).unwrap();
+        assert_eq!(handler.clients().get(&1).unwrap().pending(), Amount::ZERO);
+
+        let tx_type = (TxType::Settle).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, 1, 1, None);
+        assert!(handler.add_transaction(t).is_err());
+        assert_eq!(handler.clients().get(&1).unwrap().total(), Amount::ZERO);
+    }
+
+    #[test]
+    fn settled_deposits_leave_nothing_pending() {
+        let mut handler = ClientTransactionHandler::new();
+        for (tx, amount) in [(1, 0.7), (2, 0.1)] {
+            let t = Transaction::new(TxType::PendingDeposit.to_string(), 1, tx, Some(amount));
+            handler.add_transaction(t).unwrap();
+        }
+        let settle = |tx| Transaction::new(TxType::Settle.to_string(), 1, tx, None);
+        // in f64, 0.7 + 0.1 - 0.7 is a little less than 0.1
+        handler.add_transaction(settle(1)).unwrap();
+        handler.add_transaction(settle(2)).unwrap();
+        let client = handler.clients().get(&1).unwrap();
+        assert_eq!(client.pending(), Amount::ZERO);
+        assert_eq!(client.available(), Amount::from_f64(0.8));
+        handler.check_trial_balance().unwrap();
+    }
+
+    #[test]
+    fn pending_deposits_clear_after_the_clearing_delay() {
+        let mut handler = ClientTransactionHandler::new();
+        handler.set_clearing_delay(3600);
+        let tx_type = (TxType::PendingDeposit).to_string();
+        let t = Transaction::new(tx_type, 1, 1, Some(5.0)).with_timestamp(1000);
+        handler.add_transaction(t).unwrap();
+
+        let tx_type = (TxType::Deposit).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, 2, 2, Some(1.0)).with_timestamp(4600);
+        handler.add_transaction(t).unwrap();
+
+        let client = handler.clients().get(&1).unwrap();
+        assert_eq!(client.pending(), Amount::ZERO);
+        assert_eq!(client.available(), Amount::from_f64(5.0));
+    }
+
+    #[test]
+    fn deposits_and_withdrawals_can_be_reversed() {
+        let mut handler = ClientTransactionHandler::new();
+        let client_id = 1;
+        let tx_type = (TxType::Deposit).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, client_id, 1, Some(10.0));
+        handler.add_transaction(t).unwrap();
+        let tx_type = (TxType::Withdrawal).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, client_id, 2, Some(4.0));
+        handler.add_transaction(t).unwrap();
+
+        let tx_type = (TxType::Reversal).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, client_id, 2, None);
+        handler.add_transaction(t).unwrap();
+        assert_eq!(
+            handler.clients().get(&client_id).unwrap().available(),
+            Amount::from_f64(10.0)
+        );
+
+        let tx_type = (TxType::Reversal).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type.clone(), client_id, 1, None);
+        handler.add_transaction(t).unwrap();
+        assert_eq!(
+            handler.clients().get(&client_id).unwrap().total(),
+            Amount::ZERO
+        );
+
+        // a transaction can only be reversed once
+        let t = Transaction::new(tx_type, client_id, 1, None);
+        assert!(handler.add_transaction(t).is_err());
+        assert!(handler.transactions.get(&1).unwrap().reversed());
+    }
+
+    #[test]
+    fn withdrawals_can_be_refunded_up_to_their_remaining_amount() {
+        let mut handler = ClientTransactionHandler::new();
+        let client_id = 1;
+        let tx_type = (TxType::Deposit).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, client_id, 1, Some(10.0));
+        handler.add_transaction(t).unwrap();
+        let tx_type = (TxType::Withdrawal).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, client_id, 2, Some(4.0));
+        handler.add_transaction(t).unwrap();
+
+        let tx_type = (TxType::Refund).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type.clone(), client_id, 2, Some(3.0));
+        handler.add_transaction(t).unwrap();
+        let t = Transaction::new(tx_type.clone(), client_id, 2, Some(2.0));
+        assert!(handler.add_transaction(t).is_err());
+        // deposits can't be refunded
+        let t = Transaction::new(tx_type, client_id, 1, Some(1.0));
+        assert!(handler.add_transaction(t).is_err());
+        assert_eq!(
+            handler.clients().get(&client_id).unwrap().available(),
+            Amount::from_f64(9.0)
+        );
+
+        // the reversal only undoes what was not refunded yet
+        let tx_type = (TxType::Reversal).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, client_id, 2, None);
+        handler.add_transaction(t).unwrap();
+        assert_eq!(
+            handler.clients().get(&client_id).unwrap().available(),
+            Amount::from_f64(10.0)
+        );
+    }
+
+    #[test]
+    fn charged_back_transactions_can_not_be_reversed() {
+        let mut handler = ClientTransactionHandler::new();
+        let client_id = 1;
+        let tx_id = 2;
+        let tx_type = (TxType::Deposit).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, client_id, tx_id, Some(1.0));
+        handler.add_transaction(t).unwrap();
+        for tx_type in [TxType::Dispute, TxType::Chargeback] {
+            let tx_type = tx_type.to_string().to_ascii_lowercase();
+            let t = Transaction::new(tx_type, client_id, tx_id, None);
+            handler.add_transaction(t).unwrap();
+        }
+
+        let tx_type = (TxType::Reversal).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, client_id, tx_id, None);
+        assert!(handler.add_transaction(t).is_err());
+    }
+
+    #[test]
+    fn every_accepted_transaction_is_booked_in_the_ledger() {
+        let mut handler = ClientTransactionHandler::new();
+        handler.enable_history();
+        let client_id = 1;
+        let rows = [
+            (TxType::Deposit, 1, Some(10.0)),
+            (TxType::Deposit, 2, Some(5.0)),
+            (TxType::Withdrawal, 3, Some(3.0)),
+            (TxType::Withdrawal, 4, Some(30.0)),
+            (TxType::Dispute, 2, None),
+            (TxType::Chargeback, 2, None),
+        ];
+        for (tx_type, tx_id, amount) in rows {
+            let tx_type = tx_type.to_string().to_ascii_lowercase();
+            let _ = handler.add_transaction(Transaction::new(tx_type, client_id, tx_id, amount));
+        }
+
+        // the failed withdrawal is not booked
+        assert_eq!(handler.ledger().journal().len(), 5);
+        assert_eq!(
+            handler.ledger().balance(Account::ExternalClearing),
+            Amount::from_f64(-12.0)
+        );
+        assert_eq!(
+            handler.ledger().balance(Account::ChargebackLosses),
+            Amount::from_f64(5.0)
+        );
+        assert_eq!(
+            handler
+                .ledger()
+                .balance(Account::ClientAvailable(client_id)),
+            handler.clients().get(&client_id).unwrap().available()
+        );
+        handler.check_trial_balance().unwrap();
+    }
+
+    #[test]
+    fn the_trial_balance_is_exact_for_large_volumes() {
+        let mut handler = ClientTransactionHandler::new();
+        let mut seed = 1u64;
+        for tx_id in 1..=20_000 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let amount = (seed >> 33) % 1_000_000_000 / 10_000;
+            let amount = amount as f64 + ((seed >> 13) % 10_000) as f64 / 10_000.0;
+            let t = Transaction::new("deposit", (tx_id % 7) as u16, tx_id, Some(amount));
+            handler.add_transaction(t).unwrap();
+        }
+        assert!(handler.ledger().journal().is_empty());
+        handler.check_trial_balance().unwrap();
+    }
+
+    #[test]
+    fn statements_contain_accepted_and_rejected_rows_with_running_balances() {
+        let mut handler = ClientTransactionHandler::new();
+        handler.enable_statements();
+        let client_id = 7;
+        let rows = [
+            (TxType::Deposit, 1, Some(10.0)),
+            (TxType::Withdrawal, 2, Some(30.0)),
+            (TxType::Withdrawal, 3, Some(4.0)),
+        ];
+        for (tx_type, tx_id, amount) in rows {
+            let tx_type = tx_type.to_string().to_ascii_lowercase();
+            let _ = handler.add_transaction(Transaction::new(tx_type, client_id, tx_id, amount));
+        }
+        let tx_type = (TxType::Deposit).to_string().to_ascii_lowercase();
+        let t = Transaction::new(tx_type, 8, 4, Some(1.0));
+        handler.add_transaction(t).unwrap();
+
+        let filter = StatementFilter::default();
+        let statement: Vec<_> = handler.statement(client_id, &filter).collect();
+        assert_eq!(statement.len(), 3);
+        assert!(!statement[1].accepted);
+        assert!(statement[1].error.is_some());
+        assert_eq!(statement[1].available, Amount::from_f64(10.0));
+        assert_eq!(statement[2].available, Amount::from_f64(6.0));
+        assert_eq!(statement[2].sequence, 3);
+
+        let filter = StatementFilter {
+            tx_id: None,
+            tx_type: Some("withdrawal".to_string()),
+        };
+        assert_eq!(handler.statement(client_id, &filter).count(), 2);
+        let filter = StatementFilter {
+            tx_id: Some(3),
+            tx_type: None,
+        };
+        assert_eq!(handler.statement(client_id, &filter).count(), 1);
+    }
+
+    #[test]
+    fn balances_can_be_queried_as_of_an_earlier_row_or_time() {
+        let mut handler = ClientTransactionHandler::new();
+        handler.enable_history();
+        let client_id = 1;
+        let rows = [
+            (TxType::Deposit, 1, Some(10.0), 100),
+            (TxType::Withdrawal, 2, Some(4.0), 200),
+            (TxType::Dispute, 1, None, 300),
+        ];
+        for (tx_type, tx_id, amount, timestamp) in rows {
+            let tx_type = tx_type.to_string().to_ascii_lowercase();
+            let t = Transaction::new(tx_type, client_id, tx_id, amount).with_timestamp(timestamp);
+            let _ = handler.add_transaction(t);
+        }
+
+        let balances = handler.balances_as_of(client_id, PointInTime::Sequence(1));
+        assert_eq!(balances.available, Amount::from_f64(10.0));
+        let balances = handler.balances_as_of(client_id, PointInTime::Timestamp(250));
+        assert_eq!(balances.available, Amount::from_f64(6.0));
+        assert_eq!(balances.total(), Amount::from_f64(6.0));
+        // the dispute fails, so nothing changes at the end
+        let balances = handler.balances_as_of(client_id, PointInTime::Sequence(3));
+        assert_eq!(
+            balances.total(),
+            handler.clients().get(&client_id).unwrap().total()
+        );
+        let balances = handler.balances_as_of(client_id, PointInTime::Timestamp(50));
+        assert_eq!(balances.total(), Amount::ZERO);
+    }
+
+    #[test]
+    fn applied_transactions_are_emitted_as_account_events() {
+        let mut handler = ClientTransactionHandler::new();
+        let buffer = SharedBuffer::default();
+        handler.set_event_stream(EventStream::from_writer(buffer.clone()));
+        let rows = [
+            (TxType::Deposit, 1, Some(10.0)),
+            (TxType::Withdrawal, 2, Some(30.0)),
+            (TxType::Dispute, 1, None),
+        ];
+        for (tx_type, tx_id, amount) in rows {
+            let tx_type = tx_type.to_string().to_ascii_lowercase();
+            let _ = handler.add_transaction(Transaction::new(tx_type, 1, tx_id, amount));
+        }
+
+        let output = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        let events: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        // the failed withdrawal is not emitted
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1]["sequence"], 3);
+        assert_eq!(events[1]["operation"], "dispute");
+        assert_eq!(events[1]["before"]["available"], 10.0);
+        assert_eq!(events[1]["after"]["held"], 10.0);
+        assert_eq!(events[1]["locked"], false);
+    }
+
+    #[test]
+    fn balance_changes_made_by_the_clock_are_emitted_for_their_clients() {
+        let mut handler = ClientTransactionHandler::new();
+        handler.set_hold_ttl(100);
+        handler.set_clearing_delay(100);
+        let buffer = SharedBuffer::default();
+        handler.set_event_stream(EventStream::from_writer(buffer.clone()));
+        let rows = [
+            (1, TxType::Deposit, 1, Some(10.0), 0),
+            (2, TxType::Deposit, 2, Some(5.0), 0),
+            (2, TxType::Authorize, 3, Some(2.0), 10),
+            (2, TxType::PendingDeposit, 4, Some(1.0), 20),
+            (1, TxType::Dispute, 1, None, 60),
+            // client 2's hold expires and its deposit clears before this row
+            (1, TxType::Deposit, 5, Some(1.0), 200),
+        ];
+        for (client_id, tx_type, tx_id, amount, timestamp) in rows {
+            let t = Transaction::new(tx_type.to_string(), client_id, tx_id, amount);
+            handler
+                .add_transaction(t.with_timestamp(timestamp))
+                .unwrap();
+        }
+
+        let output = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        let events: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let summary: Vec<_> = events[5..]
+            .iter()
+            .map(|event| (event["operation"].clone(), event["client"].clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            [("void", 2), ("settle", 2), ("deposit", 1)]
+                .map(|(operation, client)| (operation.into(), client.into()))
+        );
+        assert_eq!(events[5]["sequence"], 6);
+        assert_eq!(events[5]["before"]["authorized"], 2.0);
+        assert_eq!(events[5]["after"]["available"], 5.0);
+        assert_eq!(events[6]["before"]["pending"], 1.0);
+        assert_eq!(events[6]["after"]["available"], 6.0);
+        let last = handler.clients().get(&2).unwrap().balances();
+        assert_eq!(events[6]["after"]["available"], last.available.to_f64());
+    }
+
+    /// Writes down every callback it receives, clones share what was written.
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl Recorder {
+        fn calls(&self) -> Vec<String> {
+            self.0.lock().unwrap().clone()
+        }
+
+        fn push(&mut self, call: String) {
+            self.0.lock().unwrap().push(call);
+        }
+    }
+
+    impl TransactionObserver for Recorder {
+        fn transaction_accepted(&mut self, t: &Transaction) {
+            self.push(format!("accepted {}", t.id()));
+        }
+
+        fn transaction_rejected(&mut self, t: &Transaction, _err: &TransactionError) {
+            self.push(format!("rejected {}", t.id()));
+        }
+
+        fn dispute_opened(&mut self, client_id: u16, tx_id: u32) {
+            self.push(format!("dispute opened {} {}", client_id, tx_id));
+        }
+
+        fn dispute_resolved(&mut self, client_id: u16, tx_id: u32) {
+            self.push(format!("dispute resolved {} {}", client_id, tx_id));
+        }
+
+        fn charged_back(&mut self, client_id: u16, tx_id: u32) {
+            self.push(format!("charged back {} {}", client_id, tx_id));
+        }
+
+        fn account_locked(&mut self, client_id: u16) {
+            self.push(format!("locked {}", client_id));
+        }
+    }
+
+    #[test]
+    fn synchronous_observers_are_notified_in_order() {
+        let mut handler = ClientTransactionHandler::new();
+        let recorder = Recorder::default();
+        handler.add_observer(Box::new(recorder.clone()));
+        let rows = [
+            (TxType::Deposit, 1, Some(10.0)),
+            (TxType::Withdrawal, 2, Some(30.0)),
+            (TxType::Dispute, 1, None),
+            (TxType::Resolve, 1, None),
+            (TxType::Dispute, 1, None),
+            (TxType::Chargeback, 1, None),
+        ];
+        for (i, (tx_type, tx_id, amount)) in rows.into_iter().enumerate() {
+            let tx_type = tx_type.to_string();
+            let _ = handler.add_transaction(Transaction::new(tx_type, 1, tx_id, amount));
+            // the callbacks of a row are done when add_transaction returns
+            if i == 0 {
+                assert_eq!(recorder.calls(), ["accepted 1"]);
+            }
+        }
+
+        assert_eq!(
+            recorder.calls(),
+            [
+                "accepted 1",
+                "rejected 2",
+                "accepted 1",
+                "dispute opened 1 1",
+                "accepted 1",
+                "dispute resolved 1 1",
+                "accepted 1",
+                "dispute opened 1 1",
+                "accepted 1",
+                "charged back 1 1",
+                "locked 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn observers_are_notified_through_a_bounded_channel() {
+        let mut handler = ClientTransactionHandler::new();
+        let (observer, worker) = spawn_observer(Recorder::default(), 1);
+        handler.add_observer(Box::new(observer));
+        let rows = [
+            (TxType::Deposit, 1, Some(10.0)),
+            (TxType::Withdrawal, 2, Some(30.0)),
+            (TxType::Dispute, 1, None),
+            (TxType::Chargeback, 1, None),
+        ];
+        for (tx_type, tx_id, amount) in rows {
+            let tx_type = tx_type.to_string().to_ascii_lowercase();
+            let _ = handler.add_transaction(Transaction::new(tx_type, 1, tx_id, amount));
+        }
+        drop(handler);
+
+        let recorder = worker.join().unwrap();
+        assert_eq!(
+            recorder.calls(),
+            [
+                "accepted 1",
+                "rejected 2",
+                "accepted 1",
+                "dispute opened 1 1",
+                "accepted 1",
+                "charged back 1 1",
+                "locked 1",
+            ]
+        );
+    }
+
+    /// Only accepts amounts up to 100.
+    struct AmountLimit;
+
+    impl Validator for AmountLimit {
+        fn validate(&self, t: &Transaction, _: &ValidationContext) -> Result<(), TransactionError> {
+            match t.amount() {
+                Some(amount) if amount > 100.0 => Err(TransactionError::InvalidAmount {
+                    tx_id: t.id(),
+                    client_id: t.client_id(),
+                    amount,
+                }),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_rows_are_rejected_before_they_change_state() {
+        let mut handler = ClientTransactionHandler::new();
+        handler.add_validator(Box::new(AmountLimit));
+        let deposit = (TxType::Deposit).to_string().to_ascii_lowercase();
+
+        // used to panic on the missing amount
+        let t = Transaction::new(deposit.clone(), 1, 1, None);
+        assert!(matches!(
+            handler.add_transaction(t),
+            Err(TransactionError::MissingAmount { tx_id: 1, .. })
+        ));
+        let t = Transaction::new(deposit.clone(), 1, 1, Some(500.0));
+        assert!(matches!(
+            handler.add_transaction(t),
+            Err(TransactionError::InvalidAmount { .. })
+        ));
+        assert!(handler.clients().is_empty());
+
+        handler
+            .add_transaction(Transaction::new(deposit.clone(), 1, 1, Some(50.0)))
+            .unwrap();
+        // a duplicate deposit used to be credited before its id was checked
+        let t = Transaction::new(deposit, 1, 1, Some(50.0));
+        assert!(handler.add_transaction(t).is_err());
+        assert_eq!(
+            handler.clients().get(&1).unwrap().total(),
+            Amount::from_f64(50.0)
+        );
+    }
+
+    #[test]
+    fn metrics_count_transactions_rejections_and_open_disputes() {
+        let mut handler = ClientTransactionHandler::new();
+        handler.enable_metrics();
+        let rows = [
+            (TxType::Deposit, 1, Some(10.0)),
+            (TxType::Deposit, 2, Some(10.0)),
+            (TxType::Withdrawal, 3, Some(30.0)),
+            (TxType::Dispute, 1, None),
+            (TxType::Dispute, 2, None),
+            (TxType::Resolve, 2, None),
+        ];
+        for (tx_type, tx_id, amount) in rows {
+            let tx_type = tx_type.to_string().to_ascii_lowercase();
+            let _ = handler.add_transaction(Transaction::new(tx_type, 1, tx_id, amount));
+        }
+        let _ = handler.add_transaction(Transaction::new("bogus", 1, 9, None));
+
+        let page = handler.render_metrics().unwrap();
+        assert!(page.contains("jellyfish_transactions_total{type=\"deposit\"} 2\n"));
+        assert!(page.contains("jellyfish_transactions_total{type=\"unknown\"} 1\n"));
+        assert!(page.contains("jellyfish_rejections_total{error=\"amount_not_available\"} 1\n"));
+        assert!(page.contains("jellyfish_open_disputes 1\n"));
+        assert!(page.contains("jellyfish_transactions 2\n"));
+        assert!(page.contains("jellyfish_processing_seconds_count 7\n"));
+    }
+
+    #[test]
+    fn rows_can_only_refer_to_transactions_of_their_client() {
+        let mut handler = ClientTransactionHandler::new();
+        handler
+            .add_transaction(Transaction::new("deposit", 1, 1, Some(5.0)))
+            .unwrap();
+        for tx_type in [TxType::Dispute, TxType::Resolve, TxType::Chargeback] {
+            let err = handler
+                .add_transaction(Transaction::new(tx_type.to_string(), 2, 1, None))
+                .unwrap_err();
+            assert_eq!(err.code(), format!("invalid_{}", tx_type));
+            assert!(matches!(
+                err,
+                TransactionError::InvalidDispute {
+                    state: TxState::OtherClient,
+                    ..
+                } | TransactionError::InvalidResolve {
+                    state: TxState::OtherClient,
+                    ..
+                } | TransactionError::InvalidChargeback {
+                    state: TxState::OtherClient,
+                    ..
+                }
+            ));
+        }
+        assert_eq!(
+            handler.clients().get(&1).unwrap().available(),
+            Amount::from_f64(5.0)
+        );
+        assert_eq!(handler.clients().get(&2).unwrap().total(), Amount::ZERO);
+    }
+
+    #[test]
+    fn charged_back_transactions_can_not_be_disputed_again() {
+        let mut handler = ClientTransactionHandler::new();
+        handler.set_dispute_config(DisputeConfig {
+            keep_charged_back: true,
+            ..DisputeConfig::default()
+        });
+        for (tx_type, tx_id, amount) in [
+            (TxType::Deposit, 1, Some(5.0)),
+            (TxType::Deposit, 2, Some(3.0)),
+            (TxType::Dispute, 1, None),
+            (TxType::Chargeback, 1, None),
+        ] {
+            let t = Transaction::new(tx_type.to_string(), 1, tx_id, amount);
+            handler.add_transaction(t).unwrap();
+        }
+
+        let err = handler
+            .add_transaction(Transaction::new("dispute", 1, 1, None))
+            .unwrap_err();
+        assert_eq!(
+            err,
+            TransactionError::InvalidDispute {
+                tx_id: 1,
+                client_id: 1,
+                state: TxState::ChargedBack,
+            }
+        );
+        let client = handler.clients().get(&1).unwrap();
+        assert_eq!(
+            (client.available(), client.held()),
+            (Amount::from_f64(3.0), Amount::ZERO)
+        );
+    }
+
+    #[test]
+    fn a_failed_dispute_leaves_the_transaction_undisputed() {
+        let mut handler = ClientTransactionHandler::new();
+        for (tx_type, tx_id, amount) in [
+            (TxType::Deposit, 1, Some(10.0)),
+            (TxType::Withdrawal, 2, Some(8.0)),
+        ] {
+            let t = Transaction::new(tx_type.to_string(), 1, tx_id, amount);
+            handler.add_transaction(t).unwrap();
+        }
+
+        let dispute = || Transaction::new("dispute", 1, 1, None);
+        assert!(matches!(
+            handler.add_transaction(dispute()),
+            Err(TransactionError::AmountNotAvailable { .. })
+        ));
+        assert!(!handler.transactions.get(&1).unwrap().disputed());
+        let err = handler
+            .add_transaction(Transaction::new("resolve", 1, 1, None))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            TransactionError::InvalidResolve {
+                state: TxState::NotDisputed,
+                ..
+            }
+        ));
+
+        // once the funds are there the dispute can be opened after all
+        handler
+            .add_transaction(Transaction::new("deposit", 1, 3, Some(20.0)))
+            .unwrap();
+        handler.add_transaction(dispute()).unwrap();
+        assert_eq!(
+            handler.clients().get(&1).unwrap().held(),
+            Amount::from_f64(10.0)
+        );
+    }
+
+    #[test]
+    fn resolves_and_chargebacks_release_all_that_was_held() {
+        for end in [TxType::Resolve, TxType::Chargeback] {
+            let mut handler = ClientTransactionHandler::new();
+            for (tx_type, tx_id, amount) in [
+                (TxType::Deposit, 1, Some(0.7)),
+                (TxType::Deposit, 2, Some(0.1)),
+                (TxType::Dispute, 1, None),
+                (TxType::Dispute, 2, None),
+                (TxType::Resolve, 1, None),
+            ] {
+                let t = Transaction::new(tx_type.to_string(), 1, tx_id, amount);
+                handler.add_transaction(t).unwrap();
+            }
+            // in f64, 0.7 + 0.1 - 0.7 leaves a little less than 0.1 held
+            let t = Transaction::new(end.to_string(), 1, 2, None);
+            handler.add_transaction(t).unwrap();
+
+            let client = handler.clients().get(&1).unwrap();
+            assert_eq!(client.held(), Amount::ZERO);
+            assert_eq!(client.locked(), end == TxType::Chargeback);
+            assert!(!handler.transactions.get(&2).unwrap().disputed());
+            handler.check_trial_balance().unwrap();
+        }
+    }
+
+    #[test]
+    fn failed_chargebacks_report_the_state_of_the_transaction() {
+        let mut handler = ClientTransactionHandler::new();
+        let deposit = (TxType::Deposit).to_string().to_ascii_lowercase();
+        let chargeback = (TxType::Chargeback).to_string().to_ascii_lowercase();
+        handler
+            .add_transaction(Transaction::new(deposit, 1, 1, Some(1.0)))
+            .unwrap();
+
+        let err = handler
+            .add_transaction(Transaction::new(chargeback.clone(), 1, 1, None))
+            .unwrap_err();
+        assert_eq!(
+            err,
+            TransactionError::InvalidChargeback {
+                tx_id: 1,
+                client_id: 1,
+                state: TxState::NotDisputed,
+            }
+        );
+        let err = handler
+            .add_transaction(Transaction::new(chargeback, 1, 2, None))
+            .unwrap_err();
+        assert_eq!(err.code(), "invalid_chargeback");
+        assert!(matches!(
+            err,
+            TransactionError::InvalidChargeback {
+                state: TxState::NotFound,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn disputes_follow_the_configured_policy() {
+        let mut handler = ClientTransactionHandler::new();
+        handler.set_dispute_config(DisputeConfig {
+            withdrawals: true,
+            window: Some(60),
+            keep_charged_back: false,
+        });
+        let deposit = (TxType::Deposit).to_string().to_ascii_lowercase();
+        let withdrawal = (TxType::Withdrawal).to_string().to_ascii_lowercase();
+        let dispute = (TxType::Dispute).to_string().to_ascii_lowercase();
+        let chargeback = (TxType::Chargeback).to_string().to_ascii_lowercase();
+        let t = Transaction::new(deposit.clone(), 1, 1, Some(10.0)).with_timestamp(1000);
+        handler.add_transaction(t).unwrap();
+        let t = Transaction::new(withdrawal, 1, 2, Some(4.0)).with_timestamp(1030);
+        handler.add_transaction(t).unwrap();
+
+        let t = Transaction::new(dispute.clone(), 1, 2, None).with_timestamp(1040);
+        handler.add_transaction(t).unwrap();
+        let client = handler.clients().get(&1).unwrap();
+        assert_eq!(client.available(), Amount::from_f64(6.0));
+        assert_eq!(client.held(), Amount::from_f64(4.0));
+
+        let t = Transaction::new(chargeback.clone(), 1, 2, None).with_timestamp(1050);
+        handler.add_transaction(t).unwrap();
+        let client = handler.clients().get(&1).unwrap();
+        assert_eq!(client.available(), Amount::from_f64(10.0));
+        assert_eq!(client.held(), Amount::ZERO);
+        let err = handler
+            .add_transaction(Transaction::new(chargeback, 1, 2, None))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            TransactionError::InvalidChargeback {
+                state: TxState::NotFound,
+                ..
+            }
+        ));
+        // the id stays used although the transaction is gone
+        let err = handler
+            .add_transaction(Transaction::new(deposit.clone(), 2, 2, Some(7.0)))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            TransactionError::TransactionExistsAlready { tx_id: 2, .. }
+        ));
+
+        let t = Transaction::new(deposit, 2, 3, Some(1.0)).with_timestamp(1100);
+        handler.add_transaction(t).unwrap();
+        let t = Transaction::new(dispute, 1, 1, None).with_timestamp(1100);
+        let err = handler.add_transaction(t).unwrap_err();
+        assert_eq!(
+            err,
+            TransactionError::InvalidDispute {
+                tx_id: 1,
+                client_id: 1,
+                state: TxState::Expired,
+            }
+        );
     }
 }
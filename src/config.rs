@@ -0,0 +1,217 @@
+use crate::errors::EngineError;
+use crate::logging::{LogFormat, Redaction};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Environment variables starting with this prefix override the config file,
+/// e.g. `JELLYFISH_DISPUTES_WINDOW=86400` sets `window` in the `[disputes]` section.
+pub const ENV_PREFIX: &str = "JELLYFISH_";
+
+/// Which transactions can be disputed, for how long, and what happens to them afterwards.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DisputeConfig {
+    /// Withdrawals can be disputed as well as deposits.
+    pub withdrawals: bool,
+    /// Seconds after a transaction during which it can be disputed, based on the
+    /// timestamp column. Without a window, or without timestamps, there is no limit.
+    pub window: Option<u64>,
+    /// Charged back transactions stay in memory, so later rows referring to them
+    /// are rejected as charged back rather than as unknown.
+    pub keep_charged_back: bool,
+}
+
+impl Default for DisputeConfig {
+    fn default() -> Self {
+        Self {
+            withdrawals: false,
+            window: None,
+            keep_charged_back: true,
+        }
+    }
+}
+
+/// What to do with a row that is not valid csv or can't be read as a transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InvalidRows {
+    /// Stop the engine without printing the accounts.
+    #[default]
+    Fail,
+    /// Log the row and go on with the next one.
+    Skip,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InputConfig {
+    pub invalid_rows: InvalidRows,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    /// A json array of the rows.
+    Json,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    pub format: OutputFormat,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Levels per module like `warn,jellyfish_engine::rules=debug`, defaults to RUST_LOG.
+    pub level: Option<String>,
+    pub format: LogFormat,
+    /// Logs go to this file instead of stderr.
+    pub file: Option<PathBuf>,
+    pub redact_client_ids: Redaction,
+}
+
+/// The engine's policies, read from a toml file and overridden by the environment.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub disputes: DisputeConfig,
+    pub input: InputConfig,
+    pub output: OutputConfig,
+    pub logging: LoggingConfig,
+}
+
+impl Config {
+    /// Reads the config file at `path`, if any, applies the `JELLYFISH_` variables
+    /// of `env` on top and validates the result.
+    pub fn load<I>(path: Option<&Path>, env: I) -> Result<Self, EngineError>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let text = match path {
+            Some(path) => std::fs::read_to_string(path)?,
+            None => String::new(),
+        };
+        let mut table: toml::Table = text.parse().map_err(|err: toml::de::Error| {
+            EngineError::Config(format!("invalid config file: {}", err.message()))
+        })?;
+        for (name, value) in env {
+            if let Some(key) = name.strip_prefix(ENV_PREFIX) {
+                apply_override(&mut table, &name, key, &value)?;
+            }
+        }
+
+        let config: Config = table
+            .try_into()
+            .map_err(|err: toml::de::Error| EngineError::Config(err.message().to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), EngineError> {
+        if self.disputes.window == Some(0) {
+            return Err(EngineError::Config(
+                "the dispute window has to be at least one second".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The effective settings as toml.
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).unwrap_or_else(|err| format!("{:?} ({})", self, err))
+    }
+}
+
+/// Sets `SECTION_KEY` from the environment variable `name` in the section's table.
+fn apply_override(
+    table: &mut toml::Table,
+    name: &str,
+    key: &str,
+    value: &str,
+) -> Result<(), EngineError> {
+    let key = key.to_ascii_lowercase();
+    let (section, field) = key
+        .split_once('_')
+        .ok_or_else(|| EngineError::Config(format!("`{}` names no config section", name)))?;
+    // values are typed by their looks, serde rejects the ones of the wrong type
+    let value = if let Ok(value) = value.parse::<bool>() {
+        toml::Value::Boolean(value)
+    } else if let Ok(value) = value.parse::<i64>() {
+        toml::Value::Integer(value)
+    } else {
+        toml::Value::String(value.to_string())
+    };
+    match table
+        .entry(section)
+        .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+    {
+        toml::Value::Table(section) => {
+            section.insert(field.to_string(), value);
+            Ok(())
+        }
+        _ => Err(EngineError::Config(format!(
+            "`{}` is not a config section",
+            section
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, InvalidRows, OutputFormat};
+    use crate::errors::EngineError;
+    use crate::logging::Redaction;
+    use std::io::Write;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn the_environment_overrides_the_config_file() {
+        let path =
+            std::env::temp_dir().join(format!("jellyfish-config-test-{}.toml", std::process::id()));
+        let mut file = std::fs::File::create(&path).unwrap();
+        writeln!(
+            file,
+            "[disputes]\nwindow = 60\nwithdrawals = true\n[output]\nformat = \"json\""
+        )
+        .unwrap();
+
+        let vars = env(&[
+            ("JELLYFISH_DISPUTES_WINDOW", "3600"),
+            ("JELLYFISH_INPUT_INVALID_ROWS", "skip"),
+            ("JELLYFISH_LOGGING_REDACT_CLIENT_IDS", "redact"),
+            ("HOME", "/root"),
+        ]);
+        let config = Config::load(Some(&path), vars).unwrap();
+        assert_eq!(config.disputes.window, Some(3600));
+        assert!(config.disputes.withdrawals);
+        assert!(config.disputes.keep_charged_back);
+        assert_eq!(config.input.invalid_rows, InvalidRows::Skip);
+        assert_eq!(config.output.format, OutputFormat::Json);
+        assert_eq!(config.logging.redact_client_ids, Redaction::Redact);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn invalid_settings_are_rejected_at_startup() {
+        for vars in [
+            env(&[("JELLYFISH_DISPUTES_WINDOW", "0")]),
+            env(&[("JELLYFISH_DISPUTES_WINDOWS", "10")]),
+            env(&[("JELLYFISH_OUTPUT_FORMAT", "xml")]),
+            env(&[("JELLYFISH_DISPUTES_WITHDRAWALS", "maybe")]),
+            env(&[("JELLYFISH_VERBOSE", "true")]),
+        ] {
+            let result = Config::load(None, vars);
+            assert!(matches!(result, Err(EngineError::Config(_))));
+        }
+        assert_eq!(Config::load(None, env(&[])).unwrap(), Config::default());
+    }
+}
@@ -1,31 +1,403 @@
+use crate::amount::Amount;
+use serde::Serialize;
+use std::fmt;
 use thiserror::Error;
 
-#[derive(Error, Debug)]
+/// Why the transaction a row refers to can't be acted on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TxState {
+    /// There is no transaction or hold with the id.
+    NotFound,
+    /// It belongs to another client than the row.
+    OtherClient,
+    /// Its type does not allow the operation, e.g. disputing a withdrawal.
+    WrongType,
+    /// It is a pending deposit that was not settled yet.
+    Pending,
+    /// It is a pending deposit that was settled or returned already.
+    NotPending,
+    Disputed,
+    NotDisputed,
+    ChargedBack,
+    Reversed,
+    /// It is older than the dispute window.
+    Expired,
+}
+
+impl fmt::Display for TxState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self {
+            TxState::NotFound => "does not exist",
+            TxState::OtherClient => "belongs to another client",
+            TxState::WrongType => "has the wrong type",
+            TxState::Pending => "is still pending",
+            TxState::NotPending => "is not pending",
+            TxState::Disputed => "is disputed",
+            TxState::NotDisputed => "is not disputed",
+            TxState::ChargedBack => "was charged back",
+            TxState::Reversed => "was reversed",
+            TxState::Expired => "is older than the dispute window",
+        };
+        f.write_str(state)
+    }
+}
+
+/// Why a single row was rejected. Every variant names the row's transaction and client.
+///
+/// Serialized, the variant becomes the `code` field, see `TransactionError::code`.
+#[derive(Error, Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "code", rename_all = "snake_case")]
 pub enum TransactionError {
-    #[error("Could not process because client with id `{0}` is locked")]
-    ClientIsLocked(u16),
-    #[error("Could not lock client with id `{0}`")]
-    ClientLockFailed(u16),
-    #[error("Could not unlock client with id `{0}`")]
-    ClientUnlockFailed(u16),
+    #[error("transaction {tx_id}: client with id `{client_id}` is locked")]
+    ClientIsLocked { tx_id: u32, client_id: u16 },
+    #[error("transaction {tx_id}: client with id `{client_id}` is frozen")]
+    ClientIsFrozen { tx_id: u32, client_id: u16 },
+    #[error("transaction {tx_id}: requested amount ({amount}) is not available in client account with id {client_id:?}, available: {available}")]
+    AmountNotAvailable {
+        tx_id: u32,
+        client_id: u16,
+        amount: Amount,
+        available: Amount,
+    },
+    #[error("transaction {tx_id}: requested amount ({amount}) is not held in client account with id {client_id:?}, held: {held}")]
+    AmountNotHeld {
+        tx_id: u32,
+        client_id: u16,
+        amount: Amount,
+        held: Amount,
+    },
+    #[error("transaction {tx_id}: requested amount ({amount}) is not pending in client account with id {client_id:?}, pending: {pending}")]
+    AmountNotPending {
+        tx_id: u32,
+        client_id: u16,
+        amount: Amount,
+        pending: Amount,
+    },
+    #[error("transaction {tx_id}: client with id `{client_id}` does not exist")]
+    ClientDoesNotExist { tx_id: u32, client_id: u16 },
     #[error(
-        "requested amount ({amount:?}) is not available in client account with id {client_id:?}"
+        "transaction {tx_id} of client `{client_id}` can't be created because it already exists"
     )]
-    AmountNotAvailable { client_id: u16, amount: f64 },
-    #[error("requested amount ({amount:?}) is not held in client account with id {client_id:?}")]
-    AmountNotHeld { client_id: u16, amount: f64 },
-    #[error("client does not exist")]
-    ClientDoesNotExist,
-    #[error("transaction can't be created because it already exists")]
-    TransactionExistsAlready,
-    #[error("invalid transaction record")]
-    InvalidTransactionRecord,
-    #[error("the dispute's transaction or client id is invalid")]
-    InvalidDispute,
-    #[error("the resolve's transaction or client id is invalid")]
-    InvalidResolve,
-    #[error("the chargeback's transaction or client id is invalid")]
-    InvalidChargeback,
-    #[error("ignoring unknown transaction type")]
-    UnknownTransactionType,
+    TransactionExistsAlready { tx_id: u32, client_id: u16 },
+    #[error("transaction {tx_id} of client `{client_id}` has no amount")]
+    MissingAmount { tx_id: u32, client_id: u16 },
+    #[error("transaction {tx_id} of client `{client_id}` must not have an amount, got {amount:?}")]
+    UnexpectedAmount {
+        tx_id: u32,
+        client_id: u16,
+        amount: f64,
+    },
+    #[error("the amount of transaction {tx_id} of client `{client_id}` must be greater than zero and at most 100000000000, got {amount:?}")]
+    InvalidAmount {
+        tx_id: u32,
+        client_id: u16,
+        amount: f64,
+    },
+    #[error("the amount of transaction {tx_id} of client `{client_id}` has more than four decimal places: {amount:?}")]
+    TooManyDecimalPlaces {
+        tx_id: u32,
+        client_id: u16,
+        amount: f64,
+    },
+    #[error("transaction {tx_id} of client `{client_id}` has the unknown type `{tx_type}`")]
+    UnknownTransactionType {
+        tx_id: u32,
+        client_id: u16,
+        tx_type: String,
+    },
+    #[error("client `{client_id}` can't dispute transaction {tx_id}, it {state}")]
+    InvalidDispute {
+        tx_id: u32,
+        client_id: u16,
+        state: TxState,
+    },
+    #[error("client `{client_id}` can't resolve transaction {tx_id}, it {state}")]
+    InvalidResolve {
+        tx_id: u32,
+        client_id: u16,
+        state: TxState,
+    },
+    #[error("client `{client_id}` can't charge back transaction {tx_id}, it {state}")]
+    InvalidChargeback {
+        tx_id: u32,
+        client_id: u16,
+        state: TxState,
+    },
+    #[error("client `{client_id}` can't capture authorization hold {tx_id}, it {state}")]
+    InvalidCapture {
+        tx_id: u32,
+        client_id: u16,
+        state: TxState,
+    },
+    #[error("client `{client_id}` can't void authorization hold {tx_id}, it {state}")]
+    InvalidVoid {
+        tx_id: u32,
+        client_id: u16,
+        state: TxState,
+    },
+    #[error("client `{client_id}` can't settle deposit {tx_id}, it {state}")]
+    InvalidSettle {
+        tx_id: u32,
+        client_id: u16,
+        state: TxState,
+    },
+    #[error("client `{client_id}` can't return deposit {tx_id}, it {state}")]
+    InvalidReturn {
+        tx_id: u32,
+        client_id: u16,
+        state: TxState,
+    },
+    #[error("client `{client_id}` can't reverse transaction {tx_id}, it {state}")]
+    InvalidReversal {
+        tx_id: u32,
+        client_id: u16,
+        state: TxState,
+    },
+    #[error("client `{client_id}` can't refund transaction {tx_id}, it {state}")]
+    InvalidRefund {
+        tx_id: u32,
+        client_id: u16,
+        state: TxState,
+    },
+    #[error("refund of {amount} exceeds the remaining amount ({remaining}) of withdrawal {tx_id} of client `{client_id}`")]
+    RefundExceedsRemainingAmount {
+        tx_id: u32,
+        client_id: u16,
+        amount: Amount,
+        remaining: Amount,
+    },
+    #[error("transaction {tx_id} of client `{client_id}` was rejected by the rules: {reason}")]
+    RejectedByRules {
+        tx_id: u32,
+        client_id: u16,
+        reason: String,
+    },
+    #[error("transaction {tx_id} of client `{client_id}` is held for review by the rules")]
+    HeldByRules { tx_id: u32, client_id: u16 },
+    #[error("client with id `{client_id}` was locked by the rules on transaction {tx_id}")]
+    LockedByRules { tx_id: u32, client_id: u16 },
+    #[error("the rules could not be evaluated for transaction {tx_id} of client `{client_id}`: {reason}")]
+    RuleEvaluationFailed {
+        tx_id: u32,
+        client_id: u16,
+        reason: String,
+    },
+}
+
+impl TransactionError {
+    /// A stable code for the kind of error, it is also the `code` field when serialized.
+    /// Codes are never renamed or reused, see the README for the full list.
+    pub fn code(&self) -> &'static str {
+        match self {
+            TransactionError::ClientIsLocked { .. } => "client_is_locked",
+            TransactionError::ClientIsFrozen { .. } => "client_is_frozen",
+            TransactionError::AmountNotAvailable { .. } => "amount_not_available",
+            TransactionError::AmountNotHeld { .. } => "amount_not_held",
+            TransactionError::AmountNotPending { .. } => "amount_not_pending",
+            TransactionError::ClientDoesNotExist { .. } => "client_does_not_exist",
+            TransactionError::TransactionExistsAlready { .. } => "transaction_exists_already",
+            TransactionError::MissingAmount { .. } => "missing_amount",
+            TransactionError::UnexpectedAmount { .. } => "unexpected_amount",
+            TransactionError::InvalidAmount { .. } => "invalid_amount",
+            TransactionError::TooManyDecimalPlaces { .. } => "too_many_decimal_places",
+            TransactionError::UnknownTransactionType { .. } => "unknown_transaction_type",
+            TransactionError::InvalidDispute { .. } => "invalid_dispute",
+            TransactionError::InvalidResolve { .. } => "invalid_resolve",
+            TransactionError::InvalidChargeback { .. } => "invalid_chargeback",
+            TransactionError::InvalidCapture { .. } => "invalid_capture",
+            TransactionError::InvalidVoid { .. } => "invalid_void",
+            TransactionError::InvalidSettle { .. } => "invalid_settle",
+            TransactionError::InvalidReturn { .. } => "invalid_return",
+            TransactionError::InvalidReversal { .. } => "invalid_reversal",
+            TransactionError::InvalidRefund { .. } => "invalid_refund",
+            TransactionError::RefundExceedsRemainingAmount { .. } => {
+                "refund_exceeds_remaining_amount"
+            }
+            TransactionError::RejectedByRules { .. } => "rejected_by_rules",
+            TransactionError::HeldByRules { .. } => "held_by_rules",
+            TransactionError::LockedByRules { .. } => "locked_by_rules",
+            TransactionError::RuleEvaluationFailed { .. } => "rule_evaluation_failed",
+        }
+    }
+}
+
+/// Severe errors that stop the engine instead of being logged per transaction.
+#[derive(Error, Debug)]
+pub enum EngineError {
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("invalid rules script: {0}")]
+    Rules(String),
+    #[error("the ledger does not balance, its accounts sum up to {0}")]
+    UnbalancedLedger(Amount),
+    #[error("the balances of client with id `{0}` don't match its ledger accounts")]
+    LedgerMismatch(u16),
+    #[error("invalid configuration: {0}")]
+    Config(String),
+    #[error("invalid risk threshold `{0}`, expected `<metric>>=<value>:<action>`")]
+    InvalidRiskThreshold(String),
+    #[error("invalid checkpoint: {0}")]
+    Checkpoint(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TransactionError, TxState};
+    use crate::amount::Amount;
+
+    #[test]
+    fn errors_serialize_with_their_code_and_context() {
+        let errors = [
+            TransactionError::ClientIsLocked {
+                tx_id: 1,
+                client_id: 2,
+            },
+            TransactionError::InvalidChargeback {
+                tx_id: 1,
+                client_id: 2,
+                state: TxState::NotDisputed,
+            },
+            TransactionError::RefundExceedsRemainingAmount {
+                tx_id: 1,
+                client_id: 2,
+                amount: Amount::from_f64(3.0),
+                remaining: Amount::from_f64(1.0),
+            },
+        ];
+        for err in errors {
+            let json = serde_json::to_value(&err).unwrap();
+            assert_eq!(json["code"], err.code());
+            assert_eq!(json["tx_id"], 1);
+            assert_eq!(json["client_id"], 2);
+        }
+        let err = TransactionError::InvalidChargeback {
+            tx_id: 1,
+            client_id: 2,
+            state: TxState::NotDisputed,
+        };
+        assert_eq!(serde_json::to_value(&err).unwrap()["state"], "not_disputed");
+    }
+
+    #[test]
+    fn every_code_is_the_serialized_code() {
+        use TransactionError::*;
+        let (tx_id, client_id) = (1, 2);
+        let (amount, state) = (Amount::from_f64(1.5), TxState::NotFound);
+        // one of each variant, in the order they are declared
+        let errors = [
+            ClientIsLocked { tx_id, client_id },
+            ClientIsFrozen { tx_id, client_id },
+            AmountNotAvailable {
+                tx_id,
+                client_id,
+                amount,
+                available: amount,
+            },
+            AmountNotHeld {
+                tx_id,
+                client_id,
+                amount,
+                held: amount,
+            },
+            AmountNotPending {
+                tx_id,
+                client_id,
+                amount,
+                pending: amount,
+            },
+            ClientDoesNotExist { tx_id, client_id },
+            TransactionExistsAlready { tx_id, client_id },
+            MissingAmount { tx_id, client_id },
+            UnexpectedAmount {
+                tx_id,
+                client_id,
+                amount: 1.5,
+            },
+            InvalidAmount {
+                tx_id,
+                client_id,
+                amount: 1.5,
+            },
+            TooManyDecimalPlaces {
+                tx_id,
+                client_id,
+                amount: 1.5,
+            },
+            UnknownTransactionType {
+                tx_id,
+                client_id,
+                tx_type: String::new(),
+            },
+            InvalidDispute {
+                tx_id,
+                client_id,
+                state,
+            },
+            InvalidResolve {
+                tx_id,
+                client_id,
+                state,
+            },
+            InvalidChargeback {
+                tx_id,
+                client_id,
+                state,
+            },
+            InvalidCapture {
+                tx_id,
+                client_id,
+                state,
+            },
+            InvalidVoid {
+                tx_id,
+                client_id,
+                state,
+            },
+            InvalidSettle {
+                tx_id,
+                client_id,
+                state,
+            },
+            InvalidReturn {
+                tx_id,
+                client_id,
+                state,
+            },
+            InvalidReversal {
+                tx_id,
+                client_id,
+                state,
+            },
+            InvalidRefund {
+                tx_id,
+                client_id,
+                state,
+            },
+            RefundExceedsRemainingAmount {
+                tx_id,
+                client_id,
+                amount,
+                remaining: amount,
+            },
+            RejectedByRules {
+                tx_id,
+                client_id,
+                reason: String::new(),
+            },
+            HeldByRules { tx_id, client_id },
+            LockedByRules { tx_id, client_id },
+            RuleEvaluationFailed {
+                tx_id,
+                client_id,
+                reason: String::new(),
+            },
+        ];
+        for err in errors {
+            let json = serde_json::to_value(&err).unwrap();
+            assert_eq!(json["code"], err.code(), "{:?}", err);
+        }
+    }
 }
@@ -0,0 +1,54 @@
+use crate::errors::EngineError;
+use crate::ledger::ClientBalances;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+/// The change an applied transaction made to a client's account.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AccountEvent {
+    pub sequence: u64,
+    pub tx: u32,
+    pub client: u16,
+    pub operation: String,
+    pub before: ClientBalances,
+    pub after: ClientBalances,
+    pub locked: bool,
+}
+
+/// Writes one json line per account event, flushed right away so
+/// that followers see every change as soon as it is applied.
+pub struct EventStream {
+    writer: Box<dyn Write>,
+}
+
+impl EventStream {
+    /// Connects to `path` if it is a unix socket, otherwise appends to it,
+    /// which also works for FIFOs. Opening a FIFO blocks until it has a reader.
+    pub fn open(path: &Path) -> Result<Self, EngineError> {
+        let is_socket = std::fs::metadata(path)
+            .map(|metadata| metadata.file_type().is_socket())
+            .unwrap_or(false);
+        if is_socket {
+            Ok(Self::from_writer(UnixStream::connect(path)?))
+        } else {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            Ok(Self::from_writer(BufWriter::new(file)))
+        }
+    }
+
+    pub fn from_writer(writer: impl Write + 'static) -> Self {
+        Self {
+            writer: Box::new(writer),
+        }
+    }
+
+    pub fn emit(&mut self, event: &AccountEvent) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, event)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()
+    }
+}
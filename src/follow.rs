@@ -0,0 +1,383 @@
+use crate::checkpoint::{read_checkpoint, Checkpointer, FileId, Fingerprint, InputPosition};
+use crate::config::InvalidRows;
+use crate::errors::EngineError;
+use crate::input::{parse_records, CompleteLines, TransactionReader};
+use crate::ClientTransactionHandler;
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How `follow` reads a growing file.
+#[derive(Debug, Clone)]
+pub struct FollowOptions {
+    /// How long to wait for new rows at the end of the file.
+    pub poll_interval: Duration,
+    /// How often to emit a snapshot and write the checkpoint, if rows were added since.
+    pub snapshot_interval: Duration,
+    pub checkpoint: Option<PathBuf>,
+    /// Goes on from the checkpoint instead of starting over.
+    pub resume: bool,
+    pub invalid_rows: InvalidRows,
+}
+
+/// Requests from outside the loop, usually set by signal handlers.
+#[derive(Debug, Clone, Default)]
+pub struct FollowControl {
+    /// Emits a last snapshot, writes the checkpoint and returns.
+    pub stop: Arc<AtomicBool>,
+    /// Emits a snapshot now instead of at the next interval.
+    pub snapshot: Arc<AtomicBool>,
+}
+
+/// The file at a path and how far it was read.
+struct Tail {
+    path: PathBuf,
+    source: String,
+    file: File,
+    file_id: Option<FileId>,
+    position: csv::Position,
+}
+
+impl Tail {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            source: path.display().to_string(),
+            file_id: FileId::of(&file.metadata()?),
+            file,
+            position: csv::Position::new(),
+        })
+    }
+
+    /// Continues where the checkpoint stopped. If the file was rotated since, the
+    /// rest of the old one is read first, it is looked up by its id next to the new
+    /// one. Resuming is refused if it can't be found, rather than skip its rows, if
+    /// the checkpoint is for another path, or if what was read of the file changed.
+    fn resume(
+        path: &Path,
+        saved: &InputPosition,
+        invalid_rows: InvalidRows,
+        handler: &mut ClientTransactionHandler,
+    ) -> Result<Self, EngineError> {
+        if saved.path != path {
+            return Err(EngineError::Checkpoint(format!(
+                "the checkpoint is for {}, not {}",
+                saved.path.display(),
+                path.display()
+            )));
+        }
+        let mut tail = Self::open(path)?;
+        if tail.file_id == saved.file_id {
+            if tail.file.metadata()?.len() >= saved.byte {
+                tail.ensure_unchanged(saved)?;
+                tail.position = saved.csv_position();
+            } else {
+                log::warn!(
+                    "{} was truncated since the checkpoint, reading it from the start",
+                    tail.source
+                );
+            }
+            return Ok(tail);
+        }
+
+        let rotated = saved
+            .file_id
+            .map(|file_id| find_file(path, file_id))
+            .transpose()?
+            .flatten()
+            .ok_or_else(|| {
+                EngineError::Checkpoint(format!(
+                    "{} was rotated since the checkpoint and the old file is not next to it, \
+                     refusing to skip the rest of it",
+                    tail.source
+                ))
+            })?;
+        let mut old = Self::open(&rotated)?;
+        old.ensure_unchanged(saved)?;
+        old.position = saved.csv_position();
+        log::info!(
+            "{} was rotated since the checkpoint, reading the rest of {} first",
+            tail.source,
+            old.source
+        );
+        old.read_rows(invalid_rows, handler)?;
+        Ok(tail)
+    }
+
+    /// Refuses to resume if the file no longer starts with what was read of it.
+    fn ensure_unchanged(&mut self, saved: &InputPosition) -> Result<(), EngineError> {
+        let read = Fingerprint::of_prefix(&mut self.file, saved.byte)?;
+        if saved.fingerprint != Some(read) {
+            return Err(EngineError::Checkpoint(format!(
+                "{} changed since the checkpoint, refusing to resume",
+                self.source
+            )));
+        }
+        Ok(())
+    }
+
+    fn input_position(&mut self) -> io::Result<InputPosition> {
+        let mut input = InputPosition::new(&self.path, self.file_id, &self.position);
+        input.fingerprint = Some(Fingerprint::of_prefix(
+            &mut self.file,
+            self.position.byte(),
+        )?);
+        Ok(input)
+    }
+
+    /// Processes the complete rows added since the last call and tells whether there were any.
+    fn read_rows(
+        &mut self,
+        invalid_rows: InvalidRows,
+        handler: &mut ClientTransactionHandler,
+    ) -> Result<bool, EngineError> {
+        self.file.seek(SeekFrom::Start(0))?;
+        let input = CompleteLines::new(&mut self.file);
+        let mut reader = TransactionReader::resume(input, &self.position)?;
+        parse_records(&mut reader, &self.source, invalid_rows, handler)?;
+        let position = reader.position();
+        let read = position.byte() > self.position.byte();
+        self.position = position;
+        Ok(read)
+    }
+
+    /// Starts over if the path refers to another file now, after reading what was
+    /// added to the old one, or if the file got shorter than what was read.
+    fn check_rotation(
+        &mut self,
+        invalid_rows: InvalidRows,
+        handler: &mut ClientTransactionHandler,
+    ) -> Result<(), EngineError> {
+        let metadata = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            // between moving the old file away and creating the new one
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        if FileId::of(&metadata) != self.file_id {
+            self.read_rows(invalid_rows, handler)?;
+            log::info!("{} was rotated, reading the new file", self.source);
+            *self = Self::open(&self.path)?;
+        } else if metadata.len() < self.position.byte() {
+            log::info!("{} was truncated, reading it from the start", self.source);
+            self.position = csv::Position::new();
+        }
+        Ok(())
+    }
+}
+
+/// The file in the directory of `path` with the id `file_id`, if there is one.
+fn find_file(path: &Path, file_id: FileId) -> io::Result<Option<PathBuf>> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if let Ok(metadata) = fs::metadata(&path) {
+            if metadata.is_file() && FileId::of(&metadata) == Some(file_id) {
+                return Ok(Some(path));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Processes the file at `path` and keeps processing rows as they are appended,
+/// until `control.stop` is set.
+///
+/// `snapshot` is called every `snapshot_interval` if rows were added, when
+/// `control.snapshot` is set and once more before returning. The checkpoint is
+/// written right after every snapshot, and read back when starting with `resume`.
+pub fn follow<F>(
+    path: &Path,
+    options: &FollowOptions,
+    control: &FollowControl,
+    handler: &mut ClientTransactionHandler,
+    mut snapshot: F,
+) -> Result<(), EngineError>
+where
+    F: FnMut(&ClientTransactionHandler) -> Result<(), EngineError>,
+{
+    let checkpoint = options.checkpoint.as_deref();
+    let mut tail = match checkpoint.filter(|_| options.resume) {
+        Some(checkpoint) => {
+            let saved = read_checkpoint(checkpoint, handler)?;
+            log::info!(
+                "resuming at line {} of {}",
+                saved.line,
+                saved.path.display()
+            );
+            Tail::resume(path, &saved, options.invalid_rows, handler)?
+        }
+        None => Tail::open(path)?,
+    };
+    let mut checkpointer = checkpoint.map(Checkpointer::new);
+
+    let (mut emitted, mut emitted_at) = (handler.sequence(), Instant::now());
+    loop {
+        let stop = control.stop.load(Ordering::Relaxed);
+        let read = tail.read_rows(options.invalid_rows, handler)?;
+        let due =
+            emitted_at.elapsed() >= options.snapshot_interval && handler.sequence() != emitted;
+        if stop || due || control.snapshot.swap(false, Ordering::Relaxed) {
+            handler.check_trial_balance()?;
+            snapshot(handler)?;
+            if let Some(checkpointer) = checkpointer.as_mut() {
+                checkpointer.write(&tail.input_position()?, handler)?;
+            }
+            (emitted, emitted_at) = (handler.sequence(), Instant::now());
+        }
+        if stop {
+            return Ok(());
+        }
+        if !read {
+            tail.check_rotation(options.invalid_rows, handler)?;
+            std::thread::sleep(options.poll_interval);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{follow, FollowControl, FollowOptions};
+    use crate::amount::Amount;
+    use crate::config::InvalidRows;
+    use crate::errors::EngineError;
+    use crate::ClientTransactionHandler;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    fn append(path: &std::path::Path, data: &str) {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .unwrap();
+        file.write_all(data.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn rows_are_processed_as_they_are_appended() {
+        let dir = std::env::temp_dir().join(format!("jellyfish-follow-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (path, checkpoint) = (dir.join("input.csv"), dir.join("checkpoint"));
+        append(
+            &path,
+            "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,",
+        );
+
+        let options = FollowOptions {
+            poll_interval: Duration::from_millis(1),
+            snapshot_interval: Duration::ZERO,
+            checkpoint: Some(checkpoint.clone()),
+            resume: false,
+            invalid_rows: InvalidRows::Fail,
+        };
+        let control = FollowControl::default();
+        let mut totals = Vec::new();
+        let mut handler = ClientTransactionHandler::new();
+        follow(&path, &options, &control, &mut handler, |handler| {
+            let total = handler
+                .clients()
+                .get(&1)
+                .map_or(0.0, |client| client.total().to_f64());
+            totals.push(total);
+            // the writer finishes the half written row, rotates the file and starts the next one
+            match totals.len() {
+                1 => append(&path, "2.0\n"),
+                2 => {
+                    fs::rename(&path, dir.join("input.csv.1")).unwrap();
+                    append(&dir.join("input.csv.1"), "deposit,1,3,4.0\n");
+                    append(&path, "type,client,tx,amount\ndeposit,1,4,8.0\n");
+                }
+                4 => control.stop.store(true, Ordering::Relaxed),
+                _ => {}
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(totals[..4], [1.0, 3.0, 7.0, 15.0]);
+
+        // a restart goes on after the last row of the checkpoint
+        append(&path, "deposit,1,5,16.0\n");
+        control.stop.store(true, Ordering::Relaxed);
+        let options = FollowOptions {
+            resume: true,
+            ..options
+        };
+        let mut restarted = ClientTransactionHandler::new();
+        follow(&path, &options, &control, &mut restarted, |_| Ok(())).unwrap();
+        assert_eq!(
+            restarted.clients().get(&1).unwrap().total(),
+            Amount::from_f64(31.0)
+        );
+
+        // rotated while it was down, the rest of the old file is read before the new one
+        fs::rename(&path, dir.join("input.csv.2")).unwrap();
+        append(&dir.join("input.csv.2"), "deposit,1,6,32.0\n");
+        append(&path, "type,client,tx,amount\ndeposit,1,7,64.0\n");
+        let mut restarted = ClientTransactionHandler::new();
+        follow(&path, &options, &control, &mut restarted, |_| Ok(())).unwrap();
+        assert_eq!(
+            restarted.clients().get(&1).unwrap().total(),
+            Amount::from_f64(127.0)
+        );
+
+        // without the old file, resuming would skip its rows
+        fs::rename(&path, dir.join("input.csv.3")).unwrap();
+        append(&path, "type,client,tx,amount\ndeposit,1,8,128.0\n");
+        fs::remove_file(dir.join("input.csv.3")).unwrap();
+        let mut restarted = ClientTransactionHandler::new();
+        let result = follow(&path, &options, &control, &mut restarted, |_| Ok(()));
+        assert!(matches!(result, Err(EngineError::Checkpoint(_))));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn resuming_is_refused_for_another_or_a_changed_file() {
+        let dir = std::env::temp_dir().join(format!("jellyfish-refuse-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (path, checkpoint) = (dir.join("input.csv"), dir.join("checkpoint"));
+        append(&path, "type,client,tx,amount\ndeposit,1,1,1.0\n");
+        let options = FollowOptions {
+            poll_interval: Duration::from_millis(1),
+            snapshot_interval: Duration::ZERO,
+            checkpoint: Some(checkpoint.clone()),
+            resume: false,
+            invalid_rows: InvalidRows::Fail,
+        };
+        let control = FollowControl::default();
+        control.stop.store(true, Ordering::Relaxed);
+        let mut handler = ClientTransactionHandler::new();
+        follow(&path, &options, &control, &mut handler, |_| Ok(())).unwrap();
+
+        let options = FollowOptions {
+            resume: true,
+            ..options
+        };
+        let other = dir.join("other.csv");
+        append(&other, "type,client,tx,amount\ndeposit,1,1,1.0\n");
+        let mut restarted = ClientTransactionHandler::new();
+        let result = follow(&other, &options, &control, &mut restarted, |_| Ok(()));
+        assert!(matches!(result, Err(EngineError::Checkpoint(_))));
+
+        // rewritten in place, the same file no longer starts with the rows that were read
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all(b"type,client,tx,amount\ndeposit,2").unwrap();
+        drop(file);
+        append(&path, "deposit,1,2,2.0\n");
+        let mut restarted = ClientTransactionHandler::new();
+        let result = follow(&path, &options, &control, &mut restarted, |_| Ok(()));
+        assert!(matches!(result, Err(EngineError::Checkpoint(_))));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
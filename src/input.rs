@@ -0,0 +1,525 @@
+use crate::config::InvalidRows;
+use crate::transaction::Transaction;
+use crate::ClientTransactionHandler;
+use csv::{ByteRecord, ReaderBuilder};
+use flate2::read::MultiGzDecoder;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// How an input file is compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Detected from the first bytes of the file, or from its extension if they
+    /// don't tell, so a truncated `.gz` file fails as gzip rather than as csv.
+    pub fn detect(path: &Path, head: &[u8]) -> Self {
+        if head.starts_with(GZIP_MAGIC) {
+            return Compression::Gzip;
+        }
+        if head.starts_with(ZSTD_MAGIC) {
+            return Compression::Zstd;
+        }
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("gz" | "gzip") => Compression::Gzip,
+            Some("zst" | "zstd") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+
+    /// Detected from the first bytes of the file at `path`.
+    pub fn of_file(path: &Path) -> io::Result<Self> {
+        let mut head = Vec::new();
+        File::open(path)?.take(4).read_to_end(&mut head)?;
+        Ok(Self::detect(path, &head))
+    }
+}
+
+/// Opens `path` and decompresses it on the fly if it is gzip or zstd compressed.
+pub fn open_input(path: &Path) -> io::Result<Box<dyn Read>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    Ok(match Compression::detect(path, reader.fill_buf()?) {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+    })
+}
+
+/// The files `inputs` stand for, in the given order. Directories stand for the files
+/// in them and glob patterns for the files they match, both ordered by name with
+/// numbers compared by value, so `part-2` comes before `part-10`.
+pub fn expand_inputs(inputs: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for input in inputs {
+        let pattern = input.to_string_lossy();
+        let mut matches = if input.is_dir() {
+            std::fs::read_dir(input)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .filter(|path| path.as_ref().map_or(true, |path| path.is_file()))
+                .collect::<io::Result<Vec<_>>>()?
+        } else if pattern.contains(['*', '?', '[']) {
+            let paths = glob::glob(&pattern)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            paths
+                .map(|path| path.map_err(io::Error::from))
+                .collect::<io::Result<Vec<_>>>()?
+        } else {
+            files.push(input.clone());
+            continue;
+        };
+        if matches.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no input files in `{}`", pattern),
+            ));
+        }
+        matches.sort_by(|a, b| natural_cmp(&a.to_string_lossy(), &b.to_string_lossy()));
+        files.append(&mut matches);
+    }
+    Ok(files)
+}
+
+/// Compares runs of digits by their value and everything else byte by byte.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    let digits = |s: &[u8]| s.iter().take_while(|c| c.is_ascii_digit()).count();
+    loop {
+        match (a.first(), b.first()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (number_a, rest_a) = a.split_at(digits(a));
+                let (number_b, rest_b) = b.split_at(digits(b));
+                let number_a = &number_a[number_a.iter().take_while(|&&c| c == b'0').count()..];
+                let number_b = &number_b[number_b.iter().take_while(|&&c| c == b'0').count()..];
+                let order = number_a
+                    .len()
+                    .cmp(&number_b.len())
+                    .then_with(|| number_a.cmp(number_b));
+                if order != Ordering::Equal {
+                    return order;
+                }
+                (a, b) = (rest_a, rest_b);
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(y);
+                }
+                (a, b) = (&a[1..], &b[1..]);
+            }
+        }
+    }
+}
+
+/// Processes the files one after another as a single stream of transactions.
+/// Each file has its own header, rejections are logged with the file they are in.
+pub fn parse_files(
+    files: &[PathBuf],
+    invalid_rows: InvalidRows,
+    handler: &mut ClientTransactionHandler,
+) -> Result<(), csv::Error> {
+    for path in files {
+        let source = path.display().to_string();
+        log::debug!("reading {}", source);
+        let input = open_input(path)
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", source, err)))?;
+        parse_transactions(input, &source, invalid_rows, handler)?;
+    }
+    Ok(())
+}
+
+/// Passes on complete lines only and holds back a last line without a line break,
+/// which the writer of a growing file may still be appending to.
+pub struct CompleteLines<R> {
+    inner: R,
+    buffer: Vec<u8>,
+    /// `buffer[start..end]` is complete and not passed on yet.
+    start: usize,
+    end: usize,
+}
+
+impl<R> CompleteLines<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buffer: Vec::new(),
+            start: 0,
+            end: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for CompleteLines<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        const CHUNK: usize = 64 * 1024;
+        while self.start == self.end {
+            self.buffer.drain(..self.end);
+            self.start = 0;
+            let filled = self.buffer.len();
+            self.buffer.resize(filled + CHUNK, 0);
+            let read = self.inner.read(&mut self.buffer[filled..]);
+            let read = match read {
+                Ok(read) => read,
+                Err(err) => {
+                    self.buffer.truncate(filled);
+                    return Err(err);
+                }
+            };
+            self.buffer.truncate(filled + read);
+            self.end = self
+                .buffer
+                .iter()
+                .rposition(|&b| b == b'\n')
+                .map_or(0, |i| i + 1);
+            if read == 0 && self.end == 0 {
+                return Ok(0);
+            }
+        }
+        let count = out.len().min(self.end - self.start);
+        out[..count].copy_from_slice(&self.buffer[self.start..self.start + count]);
+        self.start += count;
+        Ok(count)
+    }
+}
+
+impl<R: Seek> Seek for CompleteLines<R> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let position = match position {
+            // relative to what was passed on, not to what was read ahead
+            SeekFrom::Current(offset) => {
+                let ahead = (self.buffer.len() - self.start) as i64;
+                SeekFrom::Current(offset - ahead)
+            }
+            position => position,
+        };
+        self.buffer.clear();
+        (self.start, self.end) = (0, 0);
+        self.inner.seek(position)
+    }
+}
+
+/// Positions of the columns in the header, `None` if the header lacks them.
+#[derive(Debug, Clone, Copy, Default)]
+struct Columns {
+    tx_type: Option<usize>,
+    client: Option<usize>,
+    tx: Option<usize>,
+    amount: Option<usize>,
+    timestamp: Option<usize>,
+}
+
+impl Columns {
+    fn new(headers: &ByteRecord) -> Self {
+        let find = |name: &[u8]| headers.iter().position(|header| header == name);
+        Self {
+            tx_type: find(b"type"),
+            client: find(b"client"),
+            tx: find(b"tx"),
+            amount: find(b"amount"),
+            timestamp: find(b"timestamp"),
+        }
+    }
+}
+
+/// Reads transactions from csv without serde and without allocating per row.
+///
+/// Every row is read into the same `ByteRecord`, fields are trimmed in place
+/// and parsed straight into a `Transaction`. Rows that don't parse are handed
+/// to serde, so they fail with the same errors as `csv::Reader::deserialize`.
+pub struct TransactionReader<R> {
+    reader: csv::Reader<R>,
+    headers: ByteRecord,
+    columns: Columns,
+    record: ByteRecord,
+}
+
+impl<R: std::io::Read> TransactionReader<R> {
+    pub fn new(input: R) -> Result<Self, csv::Error> {
+        let mut reader = ReaderBuilder::new().from_reader(input);
+        let mut headers = reader.byte_headers()?.clone();
+        headers.trim();
+        Ok(Self {
+            reader,
+            columns: Columns::new(&headers),
+            headers,
+            record: ByteRecord::new(),
+        })
+    }
+
+    /// The position of the next row.
+    pub fn position(&self) -> csv::Position {
+        self.reader.position().clone()
+    }
+
+    /// The next transaction, `None` at the end of the input. The reader can
+    /// go on after errors other than io errors, with the row after the bad one.
+    pub fn next_transaction(&mut self) -> Result<Option<Transaction>, csv::Error> {
+        if !self.reader.read_byte_record(&mut self.record)? {
+            return Ok(None);
+        }
+        match self.parse_record() {
+            Some(transaction) => Ok(Some(transaction)),
+            None => {
+                self.record.trim();
+                self.record.deserialize(Some(&self.headers)).map(Some)
+            }
+        }
+    }
+
+    /// Reads past the rows before `position` without parsing them, for inputs
+    /// that can't seek. Rows that can't be read are passed over as well.
+    pub fn skip_to(&mut self, position: &csv::Position) -> Result<(), csv::Error> {
+        while self.reader.position().byte() < position.byte() {
+            match self.reader.read_byte_record(&mut self.record) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(err) if err.is_io_error() => return Err(err),
+                Err(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// The line of the row read last.
+    pub fn line(&self) -> u64 {
+        self.record.position().map_or(0, |position| position.line())
+    }
+
+    fn field(&self, column: Option<usize>) -> Option<&str> {
+        let field = self.record.get(column?)?.trim_ascii();
+        std::str::from_utf8(field).ok()
+    }
+
+    /// Empty fields are `None`, like serde does for `Option`.
+    fn optional<T: FromStr>(&self, column: Option<usize>) -> Option<Option<T>> {
+        match column.map(|column| self.field(Some(column))) {
+            None => Some(None),
+            Some(Some("")) => Some(None),
+            Some(field) => field?.parse().ok().map(Some),
+        }
+    }
+
+    fn parse_record(&self) -> Option<Transaction> {
+        let tx_type = self.field(self.columns.tx_type)?;
+        let client = self.field(self.columns.client)?.parse().ok()?;
+        let tx = self.field(self.columns.tx)?.parse().ok()?;
+        // serde requires the column, but not a value in it
+        self.columns.amount?;
+        let amount = self.optional(self.columns.amount)?;
+        let transaction = Transaction::new(tx_type, client, tx, amount);
+        Some(match self.optional(self.columns.timestamp)? {
+            Some(timestamp) => transaction.with_timestamp(timestamp),
+            None => transaction,
+        })
+    }
+}
+
+impl<R: Read + Seek> TransactionReader<R> {
+    /// Reads the header at the start of `input` and continues at `position`,
+    /// which has to be a position returned by `position` for the same input.
+    pub fn resume(input: R, position: &csv::Position) -> Result<Self, csv::Error> {
+        let mut reader = Self::new(input)?;
+        if position.byte() > 0 {
+            reader.reader.seek(position.clone())?;
+        }
+        Ok(reader)
+    }
+}
+
+/// Feeds every row of `input` to the handler and logs the rejected ones
+/// with `source` and their line number. Rows that can't be read fail the whole
+/// input, unless `invalid_rows` says to skip them.
+pub fn parse_transactions<T>(
+    input: T,
+    source: &str,
+    invalid_rows: InvalidRows,
+    handler: &mut ClientTransactionHandler,
+) -> Result<(), csv::Error>
+where
+    T: std::io::Read,
+{
+    parse_records(
+        &mut TransactionReader::new(input)?,
+        source,
+        invalid_rows,
+        handler,
+    )
+}
+
+/// Feeds the rest of `reader` to the handler, like `parse_transactions`.
+pub fn parse_records<R: Read>(
+    reader: &mut TransactionReader<R>,
+    source: &str,
+    invalid_rows: InvalidRows,
+    handler: &mut ClientTransactionHandler,
+) -> Result<(), csv::Error> {
+    parse_rows(reader, u64::MAX, source, invalid_rows, handler).map(|_| ())
+}
+
+/// Feeds up to `limit` rows of `reader` to the handler, like `parse_transactions`,
+/// and returns how many there were, skipped rows included. Fewer than `limit`
+/// means the end of the input was reached.
+pub fn parse_rows<R: Read>(
+    reader: &mut TransactionReader<R>,
+    limit: u64,
+    source: &str,
+    invalid_rows: InvalidRows,
+    handler: &mut ClientTransactionHandler,
+) -> Result<u64, csv::Error> {
+    let skip = |err: csv::Error| {
+        if invalid_rows == InvalidRows::Fail || err.is_io_error() {
+            return Err(err);
+        }
+        let line = err.position().map_or(0, |position| position.line());
+        log::error!(file = source, line, code = "invalid_row"; "skipping row: {}", err);
+        Ok(())
+    };
+
+    let mut rows = 0;
+    while rows < limit {
+        let transaction = match reader.next_transaction() {
+            Ok(Some(transaction)) => transaction,
+            Ok(None) => break,
+            Err(err) => {
+                rows += 1;
+                skip(err)?;
+                continue;
+            }
+        };
+        rows += 1;
+        let (line, tx, client) = (reader.line(), transaction.id(), transaction.client_id());
+        // only unknown types have to be copied for the log
+        let tx_type = match transaction.tx_type() {
+            Ok(tx_type) => Cow::Borrowed(tx_type.as_str()),
+            Err(_) => Cow::Owned(transaction.raw_tx_type().to_string()),
+        };
+        if let Err(err) = handler.add_transaction(transaction) {
+            log::error!(
+                file = source, line, tx, client, tx_type = tx_type.as_ref(), code = err.code();
+                "{}", err
+            );
+        }
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expand_inputs, natural_cmp, parse_files, parse_transactions, TransactionReader};
+    use crate::amount::Amount;
+    use crate::config::InvalidRows;
+    use crate::transaction::Transaction;
+    use crate::ClientTransactionHandler;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    /// An empty directory of its own for every test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("jellyfish-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+    #[test]
+    fn it_can_handle_white_space_in_csv() {
+        let data = "type, client, tx,amount\ndeposit, 1, 1, 1.0\n";
+        let mut handler = ClientTransactionHandler::new();
+        parse_transactions(data.as_bytes(), "test", InvalidRows::Fail, &mut handler).unwrap();
+        assert_eq!(
+            handler.clients().get(&1).unwrap().total(),
+            Amount::from_f64(1.0)
+        );
+    }
+
+    #[test]
+    fn invalid_rows_fail_the_input_unless_they_are_skipped() {
+        let data =
+            "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,x,2,1.0\ndeposit,1\ndeposit,1,3,2.0\n";
+        let mut handler = ClientTransactionHandler::new();
+        assert!(
+            parse_transactions(data.as_bytes(), "test", InvalidRows::Fail, &mut handler).is_err()
+        );
+
+        let mut handler = ClientTransactionHandler::new();
+        parse_transactions(data.as_bytes(), "test", InvalidRows::Skip, &mut handler).unwrap();
+        assert_eq!(
+            handler.clients().get(&1).unwrap().total(),
+            Amount::from_f64(3.0)
+        );
+    }
+
+    #[test]
+    fn the_reader_agrees_with_serde() {
+        let data = concat!(
+            " amount , tx,type,client ,timestamp\n",
+            "1.5,1,deposit,1,\n",
+            " , 2 , dispute , 1 , 20\n",
+            "2,3,teleport,2,\n",
+            "2,4,deposit,x,\n",
+            "1e400,5,deposit,3,\n",
+            "2,-6,withdrawal,3,\n",
+            "\t3.25\t,7,\twithdrawal,4,30\n",
+        );
+        let mut serde = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(data.as_bytes());
+        let expected: Vec<_> = serde
+            .deserialize::<Transaction>()
+            .map(|row| row.map_err(|err| err.to_string()))
+            .collect();
+
+        let mut reader = TransactionReader::new(data.as_bytes()).unwrap();
+        let mut actual = Vec::new();
+        while let Some(row) = reader.next_transaction().transpose() {
+            actual.push(row.map_err(|err| err.to_string()));
+        }
+        assert_eq!(actual, expected);
+        assert!(expected.iter().filter(|row| row.is_err()).count() >= 2);
+    }
+
+    #[test]
+    fn compressed_parts_are_read_as_one_stream() {
+        let dir = temp_dir("parts");
+        let part = |tx: u32| format!("type,client,tx,amount\ndeposit,1,{},1.0\n", tx);
+        let mut gzip = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        gzip.write_all(part(1).as_bytes()).unwrap();
+        std::fs::write(dir.join("part-1.csv.gz"), gzip.finish().unwrap()).unwrap();
+        let zstd = zstd::encode_all(part(2).as_bytes(), 0).unwrap();
+        // detected by its magic bytes, the extension doesn't tell
+        std::fs::write(dir.join("part-2.csv"), zstd).unwrap();
+        std::fs::write(dir.join("part-10.csv"), part(10)).unwrap();
+
+        let files = expand_inputs(&[dir.join("part-*")]).unwrap();
+        let names: Vec<_> = files.iter().map(|file| file.file_name().unwrap()).collect();
+        assert_eq!(names, ["part-1.csv.gz", "part-2.csv", "part-10.csv"]);
+        assert_eq!(expand_inputs(std::slice::from_ref(&dir)).unwrap(), files);
+        assert!(expand_inputs(&[dir.join("*.json")]).is_err());
+
+        let mut handler = ClientTransactionHandler::new();
+        parse_files(&files, InvalidRows::Fail, &mut handler).unwrap();
+        assert_eq!(
+            handler.clients().get(&1).unwrap().total(),
+            Amount::from_f64(3.0)
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn numbers_in_names_are_compared_by_value() {
+        let mut names = ["b", "a10", "a2", "a02b", "a1"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(names, ["a1", "a2", "a02b", "a10", "b"]);
+    }
+}
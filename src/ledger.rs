@@ -0,0 +1,419 @@
+use crate::amount::Amount;
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+
+/// A client's balances are checkpointed after this many entries on its accounts,
+/// so point in time queries replay at most this many entries.
+const CHECKPOINT_INTERVAL: usize = 64;
+
+/// The accounts money can be booked on.
+///
+/// Balances are kept from the client's point of view: a credit increases what
+/// an account holds and a debit decreases it, so the external accounts that
+/// fund the clients have negative balances.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Account {
+    ClientAvailable(u16),
+    ClientHeld(u16),
+    ClientAuthorized(u16),
+    ClientPending(u16),
+    /// The outside world, money enters and leaves the engine through it.
+    ExternalClearing,
+    /// Funds that were charged back to the card networks.
+    ChargebackLosses,
+    /// There are no fee transactions yet, but the account is part of the chart.
+    #[allow(dead_code)]
+    Fees,
+}
+
+impl Account {
+    /// The client owning the account, `None` for the engine's own accounts.
+    pub fn client_id(&self) -> Option<u16> {
+        match *self {
+            Account::ClientAvailable(id)
+            | Account::ClientHeld(id)
+            | Account::ClientAuthorized(id)
+            | Account::ClientPending(id) => Some(id),
+            _ => None,
+        }
+    }
+}
+
+/// Moves `amount` from the `debit` account to the `credit` account,
+/// a balanced pair of postings by construction.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Transfer {
+    pub debit: Account,
+    pub credit: Account,
+    pub amount: Amount,
+}
+
+impl Transfer {
+    pub fn new(debit: Account, credit: Account, amount: Amount) -> Self {
+        Self {
+            debit,
+            credit,
+            amount,
+        }
+    }
+}
+
+/// A transfer booked for the transaction with id `tx_id`, stamped with the
+/// ledger's clock at the time it was booked.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub tx_id: u32,
+    pub sequence: u64,
+    pub timestamp: Option<u64>,
+    pub transfer: Transfer,
+}
+
+/// The balances of a client's accounts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct ClientBalances {
+    pub available: Amount,
+    pub held: Amount,
+    pub authorized: Amount,
+    pub pending: Amount,
+}
+
+impl ClientBalances {
+    /// Pending deposits are not part of the total, see `Client::total`.
+    pub fn total(&self) -> Amount {
+        self.available + self.held + self.authorized
+    }
+
+    fn balance_mut(&mut self, account: Account) -> Option<&mut Amount> {
+        match account {
+            Account::ClientAvailable(_) => Some(&mut self.available),
+            Account::ClientHeld(_) => Some(&mut self.held),
+            Account::ClientAuthorized(_) => Some(&mut self.authorized),
+            Account::ClientPending(_) => Some(&mut self.pending),
+            _ => None,
+        }
+    }
+
+    fn apply(&mut self, client_id: u16, transfer: &Transfer) {
+        if transfer.debit.client_id() == Some(client_id) {
+            if let Some(balance) = self.balance_mut(transfer.debit) {
+                *balance -= transfer.amount;
+            }
+        }
+        if transfer.credit.client_id() == Some(client_id) {
+            if let Some(balance) = self.balance_mut(transfer.credit) {
+                *balance += transfer.amount;
+            }
+        }
+    }
+}
+
+/// A point in the input, either a row's sequence number or a timestamp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PointInTime {
+    Sequence(u64),
+    Timestamp(u64),
+}
+
+/// The journal positions of all entries on a client's accounts,
+/// with its balances after every `CHECKPOINT_INTERVAL` of them.
+#[derive(Debug, Default)]
+struct ClientHistory {
+    entries: Vec<usize>,
+    checkpoints: Vec<ClientBalances>,
+}
+
+/// Records every accepted transaction as postings and keeps the account balances.
+/// The journal of the postings, and the histories built on it, are only kept once
+/// `keep_journal` was called, so the ledger doesn't grow with the input otherwise.
+#[derive(Debug, Default)]
+pub struct Ledger {
+    journal: Option<Vec<JournalEntry>>,
+    balances: HashMap<Account, Amount>,
+    history: HashMap<u16, ClientHistory>,
+    /// The balances of the clients touched since `take_changes`, from before they were.
+    changes: Option<Vec<(u16, ClientBalances)>>,
+    sequence: u64,
+    timestamp: Option<u64>,
+}
+
+/// The clients owning the accounts of `transfer`, each once.
+fn transfer_clients(transfer: &Transfer) -> impl Iterator<Item = u16> {
+    let mut clients = [transfer.debit.client_id(), transfer.credit.client_id()];
+    if clients[0] == clients[1] {
+        clients[1] = None;
+    }
+    clients.into_iter().flatten()
+}
+
+impl Ledger {
+    /// Sets the clock that following entries are stamped with.
+    pub fn set_clock(&mut self, sequence: u64, timestamp: Option<u64>) {
+        self.sequence = sequence;
+        self.timestamp = timestamp;
+    }
+
+    /// Keeps the journal of every following entry, point in time queries need it.
+    pub fn keep_journal(&mut self) {
+        self.journal.get_or_insert_with(Vec::new);
+    }
+
+    /// Keeps the balances clients had before the following entries, see `take_changes`.
+    pub fn track_changes(&mut self) {
+        self.changes.get_or_insert_with(Vec::new);
+    }
+
+    /// The clients whose balances changed since the last call, with their balances
+    /// from before, in the order they were touched. Empty unless changes are tracked.
+    pub fn take_changes(&mut self) -> Vec<(u16, ClientBalances)> {
+        self.changes
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    pub fn record(&mut self, tx_id: u32, transfer: Transfer) {
+        if let Some(mut changes) = self.changes.take() {
+            for client_id in transfer_clients(&transfer) {
+                if !changes.iter().any(|(id, _)| *id == client_id) {
+                    changes.push((client_id, self.client_balances(client_id)));
+                }
+            }
+            self.changes = Some(changes);
+        }
+        *self.balances.entry(transfer.debit).or_default() -= transfer.amount;
+        *self.balances.entry(transfer.credit).or_default() += transfer.amount;
+        let journal = match self.journal.as_mut() {
+            Some(journal) => journal,
+            None => return,
+        };
+        let position = journal.len();
+        journal.push(JournalEntry {
+            tx_id,
+            sequence: self.sequence,
+            timestamp: self.timestamp,
+            transfer,
+        });
+
+        for client_id in transfer_clients(&transfer) {
+            let balances = self.client_balances(client_id);
+            let history = self.history.entry(client_id).or_default();
+            history.entries.push(position);
+            if history.entries.len().is_multiple_of(CHECKPOINT_INTERVAL) {
+                history.checkpoints.push(balances);
+            }
+        }
+    }
+
+    /// The current balances of a client's accounts.
+    pub fn client_balances(&self, client_id: u16) -> ClientBalances {
+        ClientBalances {
+            available: self.balance(Account::ClientAvailable(client_id)),
+            held: self.balance(Account::ClientHeld(client_id)),
+            authorized: self.balance(Account::ClientAuthorized(client_id)),
+            pending: self.balance(Account::ClientPending(client_id)),
+        }
+    }
+
+    /// Reconstructs a client's balances after all rows up to and including `point`,
+    /// starting at the closest checkpoint. Zero unless the journal is kept.
+    pub fn client_balances_as_of(&self, client_id: u16, point: PointInTime) -> ClientBalances {
+        let history = match self.history.get(&client_id) {
+            Some(history) => history,
+            None => return ClientBalances::default(),
+        };
+        let journal = self.journal();
+        let count = history.entries.partition_point(|&position| {
+            let entry = &journal[position];
+            match point {
+                PointInTime::Sequence(sequence) => entry.sequence <= sequence,
+                PointInTime::Timestamp(timestamp) => entry.timestamp.is_none_or(|t| t <= timestamp),
+            }
+        });
+
+        let checkpoint = count / CHECKPOINT_INTERVAL;
+        let mut balances = match checkpoint {
+            0 => ClientBalances::default(),
+            _ => history.checkpoints[checkpoint - 1],
+        };
+        for &position in &history.entries[checkpoint * CHECKPOINT_INTERVAL..count] {
+            balances.apply(client_id, &journal[position].transfer);
+        }
+        balances
+    }
+
+    pub fn balance(&self, account: Account) -> Amount {
+        self.balances.get(&account).copied().unwrap_or_default()
+    }
+
+    /// Empty unless the journal is kept.
+    pub fn journal(&self) -> &[JournalEntry] {
+        self.journal.as_deref().unwrap_or_default()
+    }
+
+    /// The sum of all account balances, zero for a consistent ledger.
+    pub fn trial_balance(&self) -> Amount {
+        self.balances.values().copied().sum()
+    }
+}
+
+/// A ledger is saved as its balances and its journal if it keeps one. The journal
+/// may have been started after the first entries, so it is replayed on the balances
+/// from before it, which rebuilds the histories and ends at the saved balances.
+#[derive(Deserialize)]
+struct SavedLedger {
+    balances: Vec<(Account, Amount)>,
+    journal: Option<Vec<JournalEntry>>,
+    sequence: u64,
+    timestamp: Option<u64>,
+}
+
+impl Serialize for Ledger {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut saved = serializer.serialize_struct("SavedLedger", 4)?;
+        let balances: Vec<_> = self.balances.iter().collect();
+        saved.serialize_field("balances", &balances)?;
+        saved.serialize_field("journal", &self.journal)?;
+        saved.serialize_field("sequence", &self.sequence)?;
+        saved.serialize_field("timestamp", &self.timestamp)?;
+        saved.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Ledger {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let saved = SavedLedger::deserialize(deserializer)?;
+        let mut balances: HashMap<_, _> = saved.balances.into_iter().collect();
+        for entry in saved.journal.iter().flatten() {
+            *balances.entry(entry.transfer.debit).or_default() += entry.transfer.amount;
+            *balances.entry(entry.transfer.credit).or_default() -= entry.transfer.amount;
+        }
+        let mut ledger = Ledger {
+            balances,
+            ..Ledger::default()
+        };
+        if let Some(journal) = saved.journal {
+            ledger.journal = Some(Vec::with_capacity(journal.len()));
+            for entry in journal {
+                ledger.set_clock(entry.sequence, entry.timestamp);
+                ledger.record(entry.tx_id, entry.transfer);
+            }
+        }
+        ledger.set_clock(saved.sequence, saved.timestamp);
+        Ok(ledger)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Account, Ledger, PointInTime, Transfer, CHECKPOINT_INTERVAL};
+    use crate::amount::Amount;
+
+    #[test]
+    fn transfers_move_money_between_accounts() {
+        let mut ledger = Ledger::default();
+        ledger.keep_journal();
+        ledger.record(
+            1,
+            Transfer::new(
+                Account::ExternalClearing,
+                Account::ClientAvailable(1),
+                Amount::from_f64(5.0),
+            ),
+        );
+        ledger.record(
+            2,
+            Transfer::new(
+                Account::ClientAvailable(1),
+                Account::ClientHeld(1),
+                Amount::from_f64(2.0),
+            ),
+        );
+
+        assert_eq!(
+            ledger.balance(Account::ClientAvailable(1)),
+            Amount::from_f64(3.0)
+        );
+        assert_eq!(
+            ledger.balance(Account::ClientHeld(1)),
+            Amount::from_f64(2.0)
+        );
+        assert_eq!(
+            ledger.balance(Account::ExternalClearing),
+            Amount::from_f64(-5.0)
+        );
+        assert_eq!(ledger.trial_balance(), Amount::ZERO);
+        assert_eq!(ledger.journal().len(), 2);
+    }
+
+    #[test]
+    fn a_journal_started_after_a_restore_keeps_the_balances() {
+        let deposit = |client| {
+            Transfer::new(
+                Account::ExternalClearing,
+                Account::ClientAvailable(client),
+                Amount::from_f64(1.0),
+            )
+        };
+        let mut ledger = Ledger::default();
+        ledger.record(1, deposit(1));
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&ledger, &mut cbor).unwrap();
+
+        let mut ledger: Ledger = ciborium::from_reader(cbor.as_slice()).unwrap();
+        ledger.keep_journal();
+        ledger.record(2, deposit(2));
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&ledger, &mut cbor).unwrap();
+
+        let restored: Ledger = ciborium::from_reader(cbor.as_slice()).unwrap();
+        assert_eq!(
+            restored.balance(Account::ClientAvailable(1)),
+            Amount::from_f64(1.0)
+        );
+        assert_eq!(
+            restored.balance(Account::ClientAvailable(2)),
+            Amount::from_f64(1.0)
+        );
+        assert_eq!(restored.trial_balance(), Amount::ZERO);
+        assert_eq!(restored.journal().len(), 1);
+    }
+
+    #[test]
+    fn balances_can_be_reconstructed_at_any_earlier_point() {
+        let mut ledger = Ledger::default();
+        ledger.keep_journal();
+        let rows = CHECKPOINT_INTERVAL as u64 * 3 + 5;
+        for sequence in 1..=rows {
+            ledger.set_clock(sequence, Some(sequence * 10));
+            ledger.record(
+                sequence as u32,
+                Transfer::new(
+                    Account::ExternalClearing,
+                    Account::ClientAvailable(1),
+                    Amount::from_f64(1.0),
+                ),
+            );
+            // another client in between must not show up in client 1's balances
+            ledger.record(
+                sequence as u32,
+                Transfer::new(
+                    Account::ExternalClearing,
+                    Account::ClientAvailable(2),
+                    Amount::from_f64(1.0),
+                ),
+            );
+        }
+
+        for sequence in [0, 1, 63, 64, 65, 128, 150, rows] {
+            let balances = ledger.client_balances_as_of(1, PointInTime::Sequence(sequence));
+            assert_eq!(balances.available, Amount::from_f64(sequence as f64));
+        }
+        let balances = ledger.client_balances_as_of(1, PointInTime::Timestamp(705));
+        assert_eq!(balances.available, Amount::from_f64(70.0));
+        assert_eq!(
+            ledger.client_balances_as_of(1, PointInTime::Sequence(rows)),
+            ledger.client_balances(1)
+        );
+    }
+}
@@ -0,0 +1,29 @@
+//! The jellyfish engine applies a stream of transactions to client accounts.
+//!
+//! Transactions are fed into a [`ClientTransactionHandler`], which keeps the
+//! clients, the transaction log and the ledger for the lifetime of the handler.
+
+pub mod amount;
+pub mod authorization;
+pub mod checkpoint;
+pub mod client;
+pub mod client_transaction_handler;
+pub mod config;
+pub mod errors;
+pub mod events;
+pub mod follow;
+pub mod input;
+pub mod ledger;
+pub mod logging;
+pub mod metrics;
+pub mod observer;
+pub mod risk;
+pub mod rules;
+pub mod schedule;
+pub mod statement;
+pub mod transaction;
+pub mod validation;
+pub mod workload;
+
+pub use client::Client;
+pub use client_transaction_handler::ClientTransactionHandler;
@@ -0,0 +1,214 @@
+use env_logger::filter::{Builder, Filter};
+use log::kv::{self, Key, Value, VisitSource};
+use log::{Log, Metadata, Record, SetLoggerError};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value as Json};
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Fields that identify a person and are subject to `Redaction`.
+const PII_FIELDS: [&str; 1] = ["client"];
+
+/// Whether logs are written for people or for machines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format `{}`, expected text or json", s)),
+        }
+    }
+}
+
+/// How personal fields like client ids appear in the logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Redaction {
+    /// Fields are logged as they are.
+    #[default]
+    None,
+    /// Fields are replaced by `"redacted"`.
+    Redact,
+}
+
+impl FromStr for Redaction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Redaction::None),
+            "redact" => Ok(Redaction::Redact),
+            _ => Err(format!(
+                "unknown redaction `{}`, expected none or redact",
+                s
+            )),
+        }
+    }
+}
+
+impl Redaction {
+    fn apply(&self, value: Json) -> Json {
+        match self {
+            Redaction::None => value,
+            Redaction::Redact => Json::from("redacted"),
+        }
+    }
+}
+
+/// Collects the key-values of a record into a json object.
+struct Fields<'a> {
+    fields: &'a mut Map<String, Json>,
+    redaction: Redaction,
+}
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let json = if let Some(value) = value.to_u64() {
+            Json::from(value)
+        } else if let Some(value) = value.to_i64() {
+            Json::from(value)
+        } else if let Some(value) = value.to_f64() {
+            Json::from(value)
+        } else if let Some(value) = value.to_bool() {
+            Json::from(value)
+        } else {
+            Json::from(value.to_string())
+        };
+        let json = match PII_FIELDS.contains(&key.as_str()) {
+            true => self.redaction.apply(json),
+            false => json,
+        };
+        self.fields.insert(key.to_string(), json);
+        Ok(())
+    }
+}
+
+/// Writes every record as one json object per line.
+///
+/// Levels are configured per module with the same syntax as `RUST_LOG`,
+/// e.g. `warn,jellyfish_engine::rules=debug`.
+pub struct JsonLogger {
+    filter: Filter,
+    output: Mutex<Box<dyn Write + Send>>,
+    redaction: Redaction,
+}
+
+impl JsonLogger {
+    pub fn new(filters: &str, output: Box<dyn Write + Send>, redaction: Redaction) -> Self {
+        Self {
+            filter: Builder::new().parse(filters).build(),
+            output: Mutex::new(output),
+            redaction,
+        }
+    }
+
+    /// Installs the logger as the global logger.
+    pub fn init(self) -> Result<(), SetLoggerError> {
+        log::set_max_level(self.filter.filter());
+        log::set_boxed_logger(Box::new(self))
+    }
+
+    /// The json object for `record`.
+    pub fn format(&self, record: &Record) -> Map<String, Json> {
+        let mut object = Map::new();
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |time| time.as_secs_f64());
+        object.insert("time".to_string(), Json::from(time));
+        object.insert("level".to_string(), Json::from(record.level().as_str()));
+        object.insert("target".to_string(), Json::from(record.target()));
+
+        let mut fields = Map::new();
+        let mut visitor = Fields {
+            fields: &mut fields,
+            redaction: self.redaction,
+        };
+        // the visitor itself never fails
+        let _ = record.key_values().visit(&mut visitor);
+        // error messages mention client ids, the error code has to do when they are redacted
+        let has_pii = PII_FIELDS.iter().any(|field| fields.contains_key(*field));
+        if self.redaction == Redaction::None || !has_pii {
+            object.insert("message".to_string(), Json::from(record.args().to_string()));
+        }
+        object.extend(fields);
+        object
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        let line = Json::Object(self.format(record)).to_string();
+        if let Ok(mut output) = self.output.lock() {
+            // there is nowhere left to report a failing log output to
+            let _ = writeln!(output, "{}", line);
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut output) = self.output.lock() {
+            let _ = output.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JsonLogger, Redaction};
+    use log::kv::{Key, Value};
+    use log::{Level, Record};
+    use std::io;
+
+    fn format(redaction: Redaction) -> serde_json::Map<String, serde_json::Value> {
+        let logger = JsonLogger::new("error", Box::new(io::sink()), redaction);
+        let fields = [
+            (Key::from("line"), Value::from(3u64)),
+            (Key::from("client"), Value::from(7u16)),
+            (Key::from("code"), Value::from("amount_not_available")),
+        ];
+        let record = Record::builder()
+            .level(Level::Error)
+            .target("jellyfish_engine")
+            .args(format_args!("not available for client 7"))
+            .key_values(&fields)
+            .build();
+        logger.format(&record)
+    }
+
+    #[test]
+    fn records_are_formatted_with_their_fields() {
+        let object = format(Redaction::None);
+        assert_eq!(object["level"], "ERROR");
+        assert_eq!(object["message"], "not available for client 7");
+        assert_eq!(object["line"], 3);
+        assert_eq!(object["client"], 7);
+        assert_eq!(object["code"], "amount_not_available");
+    }
+
+    #[test]
+    fn client_ids_can_be_redacted() {
+        let object = format(Redaction::Redact);
+        assert_eq!(object["client"], "redacted");
+        assert!(!object.contains_key("message"));
+        assert_eq!(object["line"], 3);
+        assert_eq!("hash".parse::<Redaction>().ok(), None);
+    }
+}
@@ -1,24 +1,243 @@
 use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-mod client;
-mod client_transaction_handler;
-mod errors;
-mod transaction;
+use jellyfish_engine::amount::Amount;
+use jellyfish_engine::checkpoint::{parse_files_with_checkpoints, CheckpointOptions};
+use jellyfish_engine::config::{Config, LoggingConfig, OutputFormat};
+use jellyfish_engine::errors::EngineError;
+use jellyfish_engine::events::EventStream;
+use jellyfish_engine::follow::{follow, FollowControl, FollowOptions};
+use jellyfish_engine::input::{expand_inputs, parse_files, Compression};
+use jellyfish_engine::ledger::PointInTime;
+use jellyfish_engine::logging::{JsonLogger, LogFormat, Redaction};
+use jellyfish_engine::metrics::MetricsEndpoint;
+use jellyfish_engine::risk::{RiskPolicy, RiskThreshold};
+use jellyfish_engine::rules::RulesEngine;
+use jellyfish_engine::statement::StatementFilter;
+use jellyfish_engine::ClientTransactionHandler;
 
-use client::Client;
-use client_transaction_handler::ClientTransactionHandler;
+use clap::{ArgGroup, Args, Parser, Subcommand};
+use serde::Serialize;
 
-use csv::{ReaderBuilder, Trim};
+#[derive(Parser)]
+#[command(
+    about = "Applies a csv file of transactions and prints the resulting accounts",
+    args_conflicts_with_subcommands = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    engine: EngineArgs,
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+/// Flags for long inputs: the follow mode, which only prints the accounts, and checkpoints.
+#[derive(Args)]
+struct RunArgs {
+    /// Keeps reading the input file as it grows until SIGINT or SIGTERM,
+    /// rotated and truncated files are read from the start
+    #[arg(long)]
+    follow: bool,
+    /// Seconds between the snapshots of the accounts while following, SIGUSR1 prints one at once
+    #[arg(long, default_value_t = 60, requires = "follow")]
+    snapshot_interval: u64,
+    /// Milliseconds to wait for new rows at the end of the file while following
+    #[arg(long, default_value_t = 500, requires = "follow")]
+    poll_interval_ms: u64,
+    /// Writes the position in the input and the engine's state to this file, with every
+    /// snapshot while following, see --resume
+    #[arg(long)]
+    checkpoint: Option<PathBuf>,
+    /// Rows of an input file between two batch checkpoints
+    #[arg(
+        long,
+        default_value_t = 1_000_000,
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "checkpoint",
+        conflicts_with = "follow"
+    )]
+    checkpoint_every: u64,
+    /// Goes on from the checkpoint instead of starting over, refused if an
+    /// input file it read changed since
+    #[arg(long, requires = "checkpoint")]
+    resume: bool,
+}
+
+/// Logging flags, they take precedence over the `[logging]` section of the config.
+#[derive(Args)]
+struct LogArgs {
+    /// Log levels per module like `warn,jellyfish_engine::rules=debug`, defaults to RUST_LOG
+    #[arg(long)]
+    log_level: Option<String>,
+    /// text or json
+    #[arg(long)]
+    log_format: Option<LogFormat>,
+    /// Writes the logs to this file instead of stderr
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+    /// How client ids appear in json logs: none or redact
+    #[arg(long)]
+    redact_client_ids: Option<Redaction>,
+}
+
+impl LogArgs {
+    fn apply(&self, config: &mut LoggingConfig) {
+        if let Some(level) = &self.log_level {
+            config.level = Some(level.clone());
+        }
+        if let Some(format) = self.log_format {
+            config.format = format;
+        }
+        if let Some(file) = &self.log_file {
+            config.file = Some(file.clone());
+        }
+        if let Some(redaction) = self.redact_client_ids {
+            config.redact_client_ids = redaction;
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Prints every row of a client with the balances after it, instead of the accounts
+    Statement {
+        /// The client to print the statement for
+        #[arg(long)]
+        client: u16,
+        /// Only show rows with this transaction id
+        #[arg(long)]
+        tx: Option<u32>,
+        /// Only show rows of this transaction type, e.g. `withdrawal`
+        #[arg(long = "type")]
+        tx_type: Option<String>,
+        #[command(flatten)]
+        engine: EngineArgs,
+    },
+    /// Prints a client's balances as of an earlier row or time, instead of the accounts
+    #[command(group(ArgGroup::new("point").required(true)))]
+    Balance {
+        /// The client to print the balances for
+        #[arg(long)]
+        client: u16,
+        /// The balances after this row of the input, counting from 1
+        #[arg(long, group = "point")]
+        sequence: Option<u64>,
+        /// The balances after all rows up to this timestamp (seconds since the unix epoch)
+        #[arg(long, group = "point")]
+        timestamp: Option<u64>,
+        #[command(flatten)]
+        engine: EngineArgs,
+    },
+}
+
+/// A client's balances at a point in time, as printed by the `balance` command.
+#[derive(Serialize)]
+struct BalanceRow {
+    client: u16,
+    available: Amount,
+    held: Amount,
+    authorized: Amount,
+    pending: Amount,
+    total: Amount,
+}
+
+#[derive(Args)]
+struct EngineArgs {
+    /// The transactions csv files, directories or glob patterns like `parts/*.csv.gz`,
+    /// processed in order. Gzip and zstd files are decompressed.
+    #[arg(default_value = "data.csv")]
+    inputs: Vec<PathBuf>,
+    /// A toml file with the engine's policies, `JELLYFISH_<SECTION>_<KEY>` variables override it
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// A rhai script that accepts, rejects, holds or locks each transaction
+    #[arg(long)]
+    rules: Option<PathBuf>,
+    /// Time budget for a single rules evaluation in milliseconds
+    #[arg(long, default_value_t = 10)]
+    rules_budget_ms: u64,
+    /// Writes the rows the rules held for review as csv to this file
+    #[arg(long, requires = "rules")]
+    held_output: Option<PathBuf>,
+    /// A risk threshold like `chargeback_ratio>=0.01:freeze`, can be repeated.
+    /// Replaces the default of locking a client on its first chargeback.
+    #[arg(long = "risk-threshold")]
+    risk_thresholds: Vec<RiskThreshold>,
+    /// Writes the dispute and chargeback counters of every client as csv to this file
+    #[arg(long)]
+    risk_report: Option<PathBuf>,
+    /// Seconds after which an authorization hold expires, based on the timestamp column
+    #[arg(long)]
+    hold_ttl: Option<u64>,
+    /// Seconds after which a pending deposit settles, based on the timestamp column
+    #[arg(long)]
+    clearing_delay: Option<u64>,
+    /// Streams one json line per applied transaction to this file, FIFO or unix socket
+    #[arg(long)]
+    events: Option<PathBuf>,
+    /// Serves Prometheus metrics over http on this address, e.g. `127.0.0.1:9090`
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+    /// Writes Prometheus metrics to this file once the input is processed
+    #[arg(long)]
+    metrics_file: Option<PathBuf>,
+    #[command(flatten)]
+    log: LogArgs,
+}
 
-fn parse_transactions<T>(input: T, handler: &mut ClientTransactionHandler) -> Result<(), csv::Error>
+/// Installs the json logger or `env_logger` as configured.
+fn init_logging(config: &LoggingConfig) -> Result<(), EngineError> {
+    let filters = config
+        .level
+        .clone()
+        .or_else(|| std::env::var("RUST_LOG").ok())
+        .unwrap_or_else(|| "error".to_string());
+    let output: Box<dyn Write + Send> = match &config.file {
+        Some(path) => Box::new(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?,
+        ),
+        None => Box::new(io::stderr()),
+    };
+    match config.format {
+        LogFormat::Json => {
+            // only fails if a logger is installed already
+            let _ = JsonLogger::new(&filters, output, config.redact_client_ids).init();
+        }
+        LogFormat::Text => env_logger::Builder::new()
+            .parse_filters(&filters)
+            .target(env_logger::Target::Pipe(output))
+            .init(),
+    }
+    Ok(())
+}
+
+/// Prints `rows` to stdout, as csv or as a json array.
+fn output_rows<T, I>(rows: I, format: OutputFormat) -> Result<(), EngineError>
 where
-    T: std::io::Read,
+    T: Serialize,
+    I: IntoIterator<Item = T>,
 {
-    let mut reader = ReaderBuilder::new().trim(Trim::All).from_reader(input);
-    for transaction in reader.deserialize() {
-        let result = handler.add_transaction(transaction?);
-        if let Err(err) = result {
-            log::error!("{}", err);
+    match format {
+        OutputFormat::Csv => {
+            let mut wtr = csv::Writer::from_writer(io::stdout());
+            for row in rows {
+                wtr.serialize(row)?;
+            }
+            wtr.flush()?;
+        }
+        OutputFormat::Json => {
+            let rows: Vec<T> = rows.into_iter().collect();
+            let mut stdout = io::stdout();
+            serde_json::to_writer(&mut stdout, &rows).map_err(io::Error::from)?;
+            writeln!(stdout)?;
         }
     }
     Ok(())
@@ -37,35 +256,220 @@ fn output_clients_to_stdout(handler: &ClientTransactionHandler) -> Result<(), cs
     Ok(())
 }
 
-fn main() -> Result<(), csv::Error> {
-    env_logger::init();
-    let file_path = std::env::args()
-        .nth(1)
-        .unwrap_or_else(|| "data.csv".to_string());
-    let mut handler = ClientTransactionHandler::new();
-    let file = std::fs::File::open(file_path)?;
-    parse_transactions(file, &mut handler)?;
-    output_clients_to_stdout(&handler)?;
+fn write_risk_report(handler: &ClientTransactionHandler, path: &Path) -> Result<(), csv::Error> {
+    let mut wtr = csv::Writer::from_path(path)?;
+    for row in handler.risk_report() {
+        wtr.serialize(row)?;
+    }
+    wtr.flush()?;
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::parse_transactions;
-    use crate::client_transaction_handler::ClientTransactionHandler;
-    #[test]
-    fn it_can_handle_white_space_in_csv() {
-        let data = "type, client, tx,amount\ndeposit, 1, 1, 1.0\n";
-        let mut handler = ClientTransactionHandler::new();
-        parse_transactions(data.as_bytes(), &mut handler).unwrap();
-        assert_eq!(handler.clients().get(&1).unwrap().total(), 1.0);
+/// Writes the rows held for review in the input format, so they can be replayed once reviewed.
+fn write_held_rows(handler: &ClientTransactionHandler, path: &Path) -> Result<(), csv::Error> {
+    let mut wtr = csv::Writer::from_path(path)?;
+    for row in handler.held_for_review() {
+        wtr.serialize(row)?;
     }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Prints the accounts of all clients.
+fn output_accounts(
+    handler: &ClientTransactionHandler,
+    format: OutputFormat,
+) -> Result<(), EngineError> {
+    match format {
+        OutputFormat::Csv => output_clients_to_stdout(handler)?,
+        OutputFormat::Json => output_rows(handler.clients().values(), format)?,
+    }
+    Ok(())
+}
 
-    #[test]
-    fn it_() {
-        let data = "type, client, tx,amount\ndeposit, 1, 1, 1.0\n";
-        let mut handler = ClientTransactionHandler::new();
-        parse_transactions(data.as_bytes(), &mut handler).unwrap();
-        assert_eq!(handler.clients().get(&1).unwrap().total(), 1.0);
+/// Stops following on SIGINT and SIGTERM, a second one ends the process at once,
+/// and prints a snapshot on SIGUSR1.
+#[cfg(unix)]
+fn register_signals(control: &FollowControl) -> io::Result<()> {
+    use signal_hook::consts::{SIGINT, SIGTERM, SIGUSR1};
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(&control.stop))?;
+        signal_hook::flag::register(signal, Arc::clone(&control.stop))?;
     }
+    signal_hook::flag::register(SIGUSR1, Arc::clone(&control.snapshot))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn register_signals(control: &FollowControl) -> io::Result<()> {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register(signal, Arc::clone(&control.stop))?;
+    }
+    Ok(())
+}
+
+/// Follows the single, uncompressed input file and prints the accounts as snapshots.
+fn follow_input(
+    inputs: &[PathBuf],
+    args: &RunArgs,
+    config: &Config,
+    handler: &mut ClientTransactionHandler,
+) -> Result<(), EngineError> {
+    let path = match inputs {
+        [path] if path.is_file() => path,
+        _ => {
+            return Err(EngineError::Config(
+                "--follow reads a single file".to_string(),
+            ))
+        }
+    };
+    if Compression::of_file(path)? != Compression::None {
+        return Err(EngineError::Config(
+            "--follow can't read compressed files".to_string(),
+        ));
+    }
+
+    let options = FollowOptions {
+        poll_interval: Duration::from_millis(args.poll_interval_ms),
+        snapshot_interval: Duration::from_secs(args.snapshot_interval),
+        checkpoint: args.checkpoint.clone(),
+        resume: args.resume,
+        invalid_rows: config.input.invalid_rows,
+    };
+    let control = FollowControl::default();
+    register_signals(&control)?;
+    let format = config.output.format;
+    follow(path, &options, &control, handler, |handler| {
+        output_accounts(handler, format)
+    })
+}
+
+/// Sets up a handler as configured by `args` and `config` and processes the input files
+/// with it, following the input file or writing checkpoints if `run` says so.
+fn run_engine(
+    args: EngineArgs,
+    run: Option<&RunArgs>,
+    config: &Config,
+    handler: &mut ClientTransactionHandler,
+) -> Result<(), EngineError> {
+    handler.set_dispute_config(config.disputes.clone());
+    if let Some(path) = &args.rules {
+        let budget = Duration::from_millis(args.rules_budget_ms);
+        handler.set_rules(RulesEngine::from_file(path, budget)?);
+    }
+    if let Some(ttl) = args.hold_ttl {
+        handler.set_hold_ttl(ttl);
+    }
+    if let Some(delay) = args.clearing_delay {
+        handler.set_clearing_delay(delay);
+    }
+    if let Some(path) = &args.events {
+        handler.set_event_stream(EventStream::open(path)?);
+    }
+    if let Some(addr) = args.metrics_addr {
+        handler.serve_metrics(MetricsEndpoint::serve(addr)?);
+    }
+    if args.metrics_file.is_some() {
+        handler.enable_metrics();
+    }
+    if !args.risk_thresholds.is_empty() {
+        handler.set_risk_policy(RiskPolicy::new(args.risk_thresholds));
+    }
+    match run {
+        Some(run) if run.follow => follow_input(&args.inputs, run, config, handler)?,
+        Some(RunArgs {
+            checkpoint: Some(path),
+            checkpoint_every,
+            resume,
+            ..
+        }) => {
+            let files = expand_inputs(&args.inputs)?;
+            let options = CheckpointOptions {
+                path: path.clone(),
+                every: *checkpoint_every,
+                resume: *resume,
+            };
+            parse_files_with_checkpoints(&files, config.input.invalid_rows, handler, &options)?;
+        }
+        _ => {
+            let files = expand_inputs(&args.inputs)?;
+            parse_files(&files, config.input.invalid_rows, handler)?;
+        }
+    }
+    handler.check_trial_balance()?;
+    handler.publish_metrics();
+    if let (Some(path), Some(page)) = (&args.metrics_file, handler.render_metrics()) {
+        std::fs::write(path, page)?;
+    }
+    if let Some(path) = &args.risk_report {
+        write_risk_report(handler, path)?;
+    }
+    if let Some(path) = &args.held_output {
+        write_held_rows(handler, path)?;
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), EngineError> {
+    let cli = Cli::parse();
+    let config = {
+        let engine = match &cli.command {
+            None => &cli.engine,
+            Some(Command::Statement { engine, .. } | Command::Balance { engine, .. }) => engine,
+        };
+        let mut config = Config::load(engine.config.as_deref(), std::env::vars())?;
+        engine.log.apply(&mut config.logging);
+        config
+    };
+    init_logging(&config.logging)?;
+    log::debug!("effective configuration:\n{}", config.to_toml());
+
+    let format = config.output.format;
+    let mut handler = ClientTransactionHandler::new();
+    match cli.command {
+        None => {
+            run_engine(cli.engine, Some(&cli.run), &config, &mut handler)?;
+            // following prints the accounts as it goes
+            if !cli.run.follow {
+                output_accounts(&handler, format)?;
+            }
+        }
+        Some(Command::Statement {
+            client,
+            tx,
+            tx_type,
+            engine,
+        }) => {
+            handler.enable_statements();
+            run_engine(engine, None, &config, &mut handler)?;
+            let filter = StatementFilter { tx_id: tx, tx_type };
+            output_rows(handler.statement(client, &filter), format)?;
+        }
+        Some(Command::Balance {
+            client,
+            sequence,
+            timestamp,
+            engine,
+        }) => {
+            handler.enable_history();
+            run_engine(engine, None, &config, &mut handler)?;
+            let point = match (sequence, timestamp) {
+                (Some(sequence), _) => PointInTime::Sequence(sequence),
+                (None, Some(timestamp)) => PointInTime::Timestamp(timestamp),
+                (None, None) => unreachable!("clap requires one of them"),
+            };
+            let balances = handler.balances_as_of(client, point);
+            let row = BalanceRow {
+                client,
+                available: balances.available,
+                held: balances.held,
+                authorized: balances.authorized,
+                pending: balances.pending,
+                total: balances.total(),
+            };
+            output_rows([row], format)?;
+        }
+    }
+    Ok(())
 }
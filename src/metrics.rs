@@ -0,0 +1,279 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How long a metrics connection may take to send its request or read the response.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Upper bounds of the latency histogram's buckets in seconds.
+const LATENCY_BUCKETS: [f64; 8] = [1e-6, 5e-6, 1e-5, 5e-5, 1e-4, 5e-4, 1e-3, 1e-2];
+
+/// Counts observations into cumulative buckets, like a Prometheus histogram.
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// Values that describe the handler's state at the time metrics are rendered.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Gauges {
+    pub open_disputes: usize,
+    pub locked_accounts: usize,
+    pub clients: usize,
+    pub transactions: usize,
+}
+
+/// Counters and the latency histogram of the processed transactions.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    processed: BTreeMap<String, u64>,
+    rejected: BTreeMap<&'static str, u64>,
+    latency: Histogram,
+    open_disputes: usize,
+}
+
+impl Metrics {
+    /// Records a processed row of type `tx_type`, which took `latency` to process.
+    pub fn record(&mut self, tx_type: &str, error: Option<&'static str>, latency: Duration) {
+        *self.processed.entry(tx_type.to_string()).or_default() += 1;
+        if let Some(error) = error {
+            *self.rejected.entry(error).or_default() += 1;
+        }
+        self.latency.observe(latency.as_secs_f64());
+    }
+
+    pub fn dispute_opened(&mut self) {
+        self.open_disputes += 1;
+    }
+
+    /// A dispute was resolved or charged back.
+    pub fn dispute_closed(&mut self) {
+        self.open_disputes = self.open_disputes.saturating_sub(1);
+    }
+
+    pub fn open_disputes(&self) -> usize {
+        self.open_disputes
+    }
+
+    pub fn processed(&self, tx_type: &str) -> u64 {
+        self.processed.get(tx_type).copied().unwrap_or_default()
+    }
+
+    pub fn rejected(&self, error: &str) -> u64 {
+        self.rejected.get(error).copied().unwrap_or_default()
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    pub fn render(&self, gauges: &Gauges) -> String {
+        // writing to a String can't fail
+        let mut out = String::new();
+        out.push_str("# HELP jellyfish_transactions_total Transactions processed by type.\n");
+        out.push_str("# TYPE jellyfish_transactions_total counter\n");
+        for (tx_type, count) in &self.processed {
+            let _ = writeln!(
+                out,
+                "jellyfish_transactions_total{{type=\"{}\"}} {}",
+                tx_type, count
+            );
+        }
+        out.push_str("# HELP jellyfish_rejections_total Rejected transactions by error.\n");
+        out.push_str("# TYPE jellyfish_rejections_total counter\n");
+        for (error, count) in &self.rejected {
+            let _ = writeln!(
+                out,
+                "jellyfish_rejections_total{{error=\"{}\"}} {}",
+                error, count
+            );
+        }
+
+        let gauges = [
+            (
+                "open_disputes",
+                "Transactions that are disputed right now.",
+                gauges.open_disputes,
+            ),
+            (
+                "locked_accounts",
+                "Locked client accounts.",
+                gauges.locked_accounts,
+            ),
+            ("clients", "Clients kept in memory.", gauges.clients),
+            (
+                "transactions",
+                "Transactions kept in memory.",
+                gauges.transactions,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            let _ = writeln!(out, "# HELP jellyfish_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE jellyfish_{} gauge", name);
+            let _ = writeln!(out, "jellyfish_{} {}", name, value);
+        }
+
+        out.push_str(
+            "# HELP jellyfish_processing_seconds Time it took to process a transaction.\n",
+        );
+        out.push_str("# TYPE jellyfish_processing_seconds histogram\n");
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(self.latency.buckets) {
+            let _ = writeln!(
+                out,
+                "jellyfish_processing_seconds_bucket{{le=\"{}\"}} {}",
+                bound, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "jellyfish_processing_seconds_bucket{{le=\"+Inf\"}} {}",
+            self.latency.count
+        );
+        let _ = writeln!(out, "jellyfish_processing_seconds_sum {}", self.latency.sum);
+        let _ = writeln!(
+            out,
+            "jellyfish_processing_seconds_count {}",
+            self.latency.count
+        );
+        out
+    }
+}
+
+/// Serves the latest published metrics to every HTTP request on a local address.
+pub struct MetricsEndpoint {
+    page: Arc<Mutex<String>>,
+    addr: SocketAddr,
+}
+
+impl MetricsEndpoint {
+    /// Binds `addr` and answers requests on a background thread.
+    pub fn serve(addr: SocketAddr) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let page = Arc::new(Mutex::new(String::new()));
+        let shared = Arc::clone(&page);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        log::error!("could not accept metrics connection: {}", err);
+                        continue;
+                    }
+                };
+                // a slow or silent client must not hold up the next scrape
+                let page = Arc::clone(&shared);
+                thread::spawn(move || {
+                    if let Err(err) = answer(stream, &page) {
+                        log::error!("could not answer metrics request: {}", err);
+                    }
+                });
+            }
+        });
+        Ok(Self { page, addr })
+    }
+
+    /// The address the endpoint listens on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Replaces the page served to following requests.
+    pub fn publish(&self, page: String) {
+        if let Ok(mut current) = self.page.lock() {
+            *current = page;
+        }
+    }
+}
+
+/// Sends `page` in response to whatever request arrives on `stream`.
+fn answer(mut stream: TcpStream, page: &Mutex<String>) -> io::Result<()> {
+    stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+    stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+    // the request itself does not matter, every path gets the metrics
+    let mut request = [0; 1024];
+    let _ = stream.read(&mut request);
+    let body = page.lock().map(|page| page.clone()).unwrap_or_default();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Gauges, Metrics, MetricsEndpoint};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    #[test]
+    fn metrics_are_rendered_in_the_prometheus_text_format() {
+        let mut metrics = Metrics::default();
+        metrics.record("deposit", None, Duration::from_micros(3));
+        metrics.record(
+            "withdrawal",
+            Some("amount_not_available"),
+            Duration::from_millis(2),
+        );
+        let gauges = Gauges {
+            clients: 1,
+            transactions: 1,
+            ..Gauges::default()
+        };
+
+        let page = metrics.render(&gauges);
+        assert!(page.contains("jellyfish_transactions_total{type=\"deposit\"} 1\n"));
+        assert!(page.contains("jellyfish_rejections_total{error=\"amount_not_available\"} 1\n"));
+        assert!(page.contains("jellyfish_clients 1\n"));
+        assert!(page.contains("jellyfish_processing_seconds_bucket{le=\"0.000005\"} 1\n"));
+        assert!(page.contains("jellyfish_processing_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(page.contains("jellyfish_processing_seconds_count 2\n"));
+    }
+
+    #[test]
+    fn the_endpoint_serves_the_latest_published_metrics() {
+        let endpoint = MetricsEndpoint::serve("127.0.0.1:0".parse().unwrap()).unwrap();
+        endpoint.publish("jellyfish_clients 3\n".to_string());
+
+        let mut stream = TcpStream::connect(endpoint.addr()).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("\r\n\r\njellyfish_clients 3\n"));
+    }
+
+    #[test]
+    fn a_silent_connection_does_not_block_other_scrapes() {
+        let endpoint = MetricsEndpoint::serve("127.0.0.1:0".parse().unwrap()).unwrap();
+        endpoint.publish("jellyfish_clients 3\n".to_string());
+
+        let _silent = TcpStream::connect(endpoint.addr()).unwrap();
+        let mut stream = TcpStream::connect(endpoint.addr()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("jellyfish_clients 3\n"));
+    }
+}
@@ -0,0 +1,112 @@
+use crate::errors::TransactionError;
+use crate::transaction::Transaction;
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread::{self, JoinHandle};
+
+/// Receives callbacks about the lifecycle of transactions and accounts.
+///
+/// Every method does nothing by default, so observers only implement what they need.
+/// Observers run synchronously inside `ClientTransactionHandler::add_transaction`,
+/// use [`spawn_observer`] to run them on their own thread instead.
+pub trait TransactionObserver {
+    fn transaction_accepted(&mut self, _t: &Transaction) {}
+
+    fn transaction_rejected(&mut self, _t: &Transaction, _err: &TransactionError) {}
+
+    fn dispute_opened(&mut self, _client_id: u16, _tx_id: u32) {}
+
+    fn dispute_resolved(&mut self, _client_id: u16, _tx_id: u32) {}
+
+    fn charged_back(&mut self, _client_id: u16, _tx_id: u32) {}
+
+    fn account_locked(&mut self, _client_id: u16) {}
+}
+
+/// An owned lifecycle callback, as it is sent to asynchronous observers.
+#[derive(Debug, Clone)]
+pub enum LifecycleEvent {
+    Accepted(Transaction),
+    Rejected(Transaction, TransactionError),
+    DisputeOpened { client_id: u16, tx_id: u32 },
+    DisputeResolved { client_id: u16, tx_id: u32 },
+    ChargedBack { client_id: u16, tx_id: u32 },
+    AccountLocked { client_id: u16 },
+}
+
+impl LifecycleEvent {
+    /// Calls the matching method of `observer`.
+    pub fn notify(&self, observer: &mut dyn TransactionObserver) {
+        match self {
+            LifecycleEvent::Accepted(t) => observer.transaction_accepted(t),
+            LifecycleEvent::Rejected(t, err) => observer.transaction_rejected(t, err),
+            LifecycleEvent::DisputeOpened { client_id, tx_id } => {
+                observer.dispute_opened(*client_id, *tx_id)
+            }
+            LifecycleEvent::DisputeResolved { client_id, tx_id } => {
+                observer.dispute_resolved(*client_id, *tx_id)
+            }
+            LifecycleEvent::ChargedBack { client_id, tx_id } => {
+                observer.charged_back(*client_id, *tx_id)
+            }
+            LifecycleEvent::AccountLocked { client_id } => observer.account_locked(*client_id),
+        }
+    }
+}
+
+/// Forwards every callback into a bounded channel. Once the channel is full
+/// the handler blocks until the receiving side caught up.
+pub struct ChannelObserver {
+    sender: SyncSender<LifecycleEvent>,
+}
+
+impl ChannelObserver {
+    fn send(&self, event: LifecycleEvent) {
+        if self.sender.send(event).is_err() {
+            log::error!("the asynchronous observer has stopped, dropping lifecycle event");
+        }
+    }
+}
+
+impl TransactionObserver for ChannelObserver {
+    fn transaction_accepted(&mut self, t: &Transaction) {
+        self.send(LifecycleEvent::Accepted(t.clone()));
+    }
+
+    fn transaction_rejected(&mut self, t: &Transaction, err: &TransactionError) {
+        self.send(LifecycleEvent::Rejected(t.clone(), err.clone()));
+    }
+
+    fn dispute_opened(&mut self, client_id: u16, tx_id: u32) {
+        self.send(LifecycleEvent::DisputeOpened { client_id, tx_id });
+    }
+
+    fn dispute_resolved(&mut self, client_id: u16, tx_id: u32) {
+        self.send(LifecycleEvent::DisputeResolved { client_id, tx_id });
+    }
+
+    fn charged_back(&mut self, client_id: u16, tx_id: u32) {
+        self.send(LifecycleEvent::ChargedBack { client_id, tx_id });
+    }
+
+    fn account_locked(&mut self, client_id: u16) {
+        self.send(LifecycleEvent::AccountLocked { client_id });
+    }
+}
+
+/// Runs `observer` on its own thread, fed through a channel of `capacity` events.
+///
+/// Register the returned `ChannelObserver` with the handler. The thread ends once
+/// the handler is dropped and hands the observer back through the join handle.
+pub fn spawn_observer<O>(mut observer: O, capacity: usize) -> (ChannelObserver, JoinHandle<O>)
+where
+    O: TransactionObserver + Send + 'static,
+{
+    let (sender, receiver) = sync_channel::<LifecycleEvent>(capacity);
+    let handle = thread::spawn(move || {
+        for event in receiver {
+            event.notify(&mut observer);
+        }
+        observer
+    });
+    (ChannelObserver { sender }, handle)
+}
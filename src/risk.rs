@@ -0,0 +1,217 @@
+use crate::errors::EngineError;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Per client counters of the dispute history.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RiskCounters {
+    deposits: u32,
+    disputes: u32,
+    chargebacks: u32,
+}
+
+impl RiskCounters {
+    pub fn record_deposit(&mut self) {
+        self.deposits += 1;
+    }
+
+    pub fn record_dispute(&mut self) {
+        self.disputes += 1;
+    }
+
+    pub fn record_chargeback(&mut self) {
+        self.chargebacks += 1;
+    }
+
+    pub fn deposits(&self) -> u32 {
+        self.deposits
+    }
+
+    pub fn disputes(&self) -> u32 {
+        self.disputes
+    }
+
+    pub fn chargebacks(&self) -> u32 {
+        self.chargebacks
+    }
+
+    /// Disputes per deposit, 0 if the client never deposited.
+    pub fn dispute_ratio(&self) -> f64 {
+        ratio(self.disputes, self.deposits)
+    }
+
+    /// Chargebacks per deposit, 0 if the client never deposited.
+    pub fn chargeback_ratio(&self) -> f64 {
+        ratio(self.chargebacks, self.deposits)
+    }
+
+    fn value(&self, metric: RiskMetric) -> f64 {
+        match metric {
+            RiskMetric::Disputes => self.disputes as f64,
+            RiskMetric::Chargebacks => self.chargebacks as f64,
+            RiskMetric::DisputeRatio => self.dispute_ratio(),
+            RiskMetric::ChargebackRatio => self.chargeback_ratio(),
+        }
+    }
+}
+
+fn ratio(count: u32, deposits: u32) -> f64 {
+    if deposits == 0 {
+        0.0
+    } else {
+        count as f64 / deposits as f64
+    }
+}
+
+/// What happens to a client that crosses a threshold, ordered by severity.
+///
+/// A flagged client only shows up in the risk report, a frozen client can't
+/// withdraw anymore and a locked client can't do anything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RiskAction {
+    Flag,
+    Freeze,
+    Lock,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RiskMetric {
+    Disputes,
+    Chargebacks,
+    DisputeRatio,
+    ChargebackRatio,
+}
+
+/// Triggers `action` once `metric` reaches `at_least`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RiskThreshold {
+    pub metric: RiskMetric,
+    pub at_least: f64,
+    pub action: RiskAction,
+}
+
+/// Parses thresholds written as `<metric>>=<value>:<action>`, e.g. `chargeback_ratio>=0.01:freeze`.
+impl FromStr for RiskThreshold {
+    type Err = EngineError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let invalid = || EngineError::InvalidRiskThreshold(input.to_string());
+        let (condition, action) = input.split_once(':').ok_or_else(invalid)?;
+        let (metric, at_least) = condition.split_once(">=").ok_or_else(invalid)?;
+        let metric = match metric.trim() {
+            "disputes" => RiskMetric::Disputes,
+            "chargebacks" => RiskMetric::Chargebacks,
+            "dispute_ratio" => RiskMetric::DisputeRatio,
+            "chargeback_ratio" => RiskMetric::ChargebackRatio,
+            _ => return Err(invalid()),
+        };
+        let action = match action.trim() {
+            "flag" => RiskAction::Flag,
+            "freeze" => RiskAction::Freeze,
+            "lock" => RiskAction::Lock,
+            _ => return Err(invalid()),
+        };
+        Ok(Self {
+            metric,
+            at_least: at_least.trim().parse().map_err(|_| invalid())?,
+            action,
+        })
+    }
+}
+
+/// Decides what happens to a client after a dispute or chargeback.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RiskPolicy {
+    thresholds: Vec<RiskThreshold>,
+}
+
+impl RiskPolicy {
+    pub fn new(thresholds: Vec<RiskThreshold>) -> Self {
+        Self { thresholds }
+    }
+
+    /// Returns the most severe action of all thresholds the counters reached.
+    pub fn evaluate(&self, counters: &RiskCounters) -> Option<RiskAction> {
+        self.thresholds
+            .iter()
+            .filter(|t| counters.value(t.metric) >= t.at_least)
+            .map(|t| t.action)
+            .max()
+    }
+}
+
+impl Default for RiskPolicy {
+    /// Locks a client on its first chargeback.
+    fn default() -> Self {
+        Self::new(vec![RiskThreshold {
+            metric: RiskMetric::Chargebacks,
+            at_least: 1.0,
+            action: RiskAction::Lock,
+        }])
+    }
+}
+
+/// A row of the risk report.
+#[derive(Debug, Serialize)]
+pub struct RiskReportRow {
+    pub client: u16,
+    pub deposits: u32,
+    pub disputes: u32,
+    pub chargebacks: u32,
+    pub dispute_ratio: f64,
+    pub chargeback_ratio: f64,
+    pub flagged: bool,
+    pub frozen: bool,
+    pub locked: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RiskAction, RiskCounters, RiskMetric, RiskPolicy, RiskThreshold};
+
+    #[test]
+    fn the_default_policy_locks_on_the_first_chargeback() {
+        let mut counters = RiskCounters::default();
+        counters.record_deposit();
+        counters.record_dispute();
+        assert_eq!(RiskPolicy::default().evaluate(&counters), None);
+        counters.record_chargeback();
+        assert_eq!(
+            RiskPolicy::default().evaluate(&counters),
+            Some(RiskAction::Lock)
+        );
+    }
+
+    #[test]
+    fn the_most_severe_reached_threshold_wins() {
+        let policy = RiskPolicy::new(vec![
+            "dispute_ratio>=0.5:flag".parse().unwrap(),
+            "disputes>=2:freeze".parse().unwrap(),
+            "chargebacks>=3:lock".parse().unwrap(),
+        ]);
+        let mut counters = RiskCounters::default();
+        counters.record_deposit();
+        counters.record_deposit();
+        counters.record_dispute();
+        assert_eq!(policy.evaluate(&counters), Some(RiskAction::Flag));
+        counters.record_dispute();
+        assert_eq!(policy.evaluate(&counters), Some(RiskAction::Freeze));
+    }
+
+    #[test]
+    fn thresholds_can_be_parsed() {
+        assert_eq!(
+            "chargeback_ratio >= 0.01 : freeze"
+                .parse::<RiskThreshold>()
+                .unwrap(),
+            RiskThreshold {
+                metric: RiskMetric::ChargebackRatio,
+                at_least: 0.01,
+                action: RiskAction::Freeze,
+            }
+        );
+        assert!("chargebacks>1:lock".parse::<RiskThreshold>().is_err());
+        assert!("chargebacks>=1:ban".parse::<RiskThreshold>().is_err());
+    }
+}
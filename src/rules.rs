@@ -0,0 +1,203 @@
+use crate::errors::{EngineError, TransactionError};
+use crate::transaction::Transaction;
+use crate::Client;
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{Array, Dynamic, Engine, Map, Scope, AST};
+use std::cell::Cell;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// The outcome of evaluating the rules script for a single transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuleDecision {
+    Accept,
+    Reject(String),
+    Hold,
+    Lock,
+}
+
+/// Runs a user supplied rhai script before each transaction.
+///
+/// The script sees the variables `tx`, `client` and `history` and decides
+/// by calling one of `accept()`, `reject(reason)`, `hold()` or `lock()`.
+/// A script that finishes without a decision accepts the transaction.
+pub struct RulesEngine {
+    engine: Engine,
+    ast: AST,
+    started: Rc<Cell<Instant>>,
+}
+
+impl RulesEngine {
+    pub fn from_file(path: &Path, time_budget: Duration) -> Result<Self, EngineError> {
+        let script = std::fs::read_to_string(path)?;
+        Self::from_script(&script, time_budget)
+    }
+
+    pub fn from_script(script: &str, time_budget: Duration) -> Result<Self, EngineError> {
+        let started = Rc::new(Cell::new(Instant::now()));
+        let mut engine = Engine::new();
+
+        // sandbox: no module imports, no dynamic code and bounded resources
+        engine
+            .set_module_resolver(DummyModuleResolver::new())
+            .disable_symbol("eval")
+            .set_max_call_levels(32)
+            .set_max_expr_depths(64, 32)
+            .set_max_string_size(4096)
+            .set_max_array_size(4096)
+            .set_max_map_size(256)
+            .on_print(|s| log::debug!("rules: {}", s))
+            .on_debug(|s, _, _| log::debug!("rules: {}", s));
+
+        let clock = Rc::clone(&started);
+        engine.on_progress(move |_| {
+            if clock.get().elapsed() > time_budget {
+                Some(Dynamic::UNIT)
+            } else {
+                None
+            }
+        });
+
+        engine
+            .register_type_with_name::<RuleDecision>("RuleDecision")
+            .register_fn("accept", || RuleDecision::Accept)
+            .register_fn("reject", |reason: &str| {
+                RuleDecision::Reject(reason.to_string())
+            })
+            .register_fn("hold", || RuleDecision::Hold)
+            .register_fn("lock", || RuleDecision::Lock);
+
+        let ast = engine
+            .compile(script)
+            .map_err(|err| EngineError::Rules(err.to_string()))?;
+
+        Ok(Self {
+            engine,
+            ast,
+            started,
+        })
+    }
+
+    /// Evaluates the script for `t`. Script failures, including an exceeded
+    /// time budget, are reported as errors so the transaction is not applied.
+    pub fn evaluate(
+        &self,
+        t: &Transaction,
+        client: &Client,
+        history: &[Transaction],
+    ) -> Result<RuleDecision, TransactionError> {
+        let mut scope = Scope::new();
+        scope.push_constant("tx", transaction_to_map(t));
+        scope.push_constant("client", client_to_map(client));
+        scope.push_constant(
+            "history",
+            history
+                .iter()
+                .map(|t| Dynamic::from_map(transaction_to_map(t)))
+                .collect::<Array>(),
+        );
+
+        self.started.set(Instant::now());
+        let result = self
+            .engine
+            .eval_ast_with_scope::<Dynamic>(&mut scope, &self.ast)
+            .map_err(|err| TransactionError::RuleEvaluationFailed {
+                tx_id: t.id(),
+                client_id: t.client_id(),
+                reason: err.to_string(),
+            })?;
+
+        if result.is_unit() {
+            Ok(RuleDecision::Accept)
+        } else {
+            result
+                .try_cast::<RuleDecision>()
+                .ok_or(TransactionError::RuleEvaluationFailed {
+                    tx_id: t.id(),
+                    client_id: t.client_id(),
+                    reason: "script did not return a decision".to_string(),
+                })
+        }
+    }
+}
+
+fn transaction_to_map(t: &Transaction) -> Map {
+    let mut map = Map::new();
+    map.insert("type".into(), t.raw_tx_type().into());
+    map.insert("client".into(), (t.client_id() as i64).into());
+    map.insert("tx".into(), (t.id() as i64).into());
+    // rows without an amount, like disputes, have 0.0 so comparisons don't fail
+    map.insert("amount".into(), t.amount().unwrap_or_default().into());
+    map.insert("has_amount".into(), t.amount().is_some().into());
+    map
+}
+
+fn client_to_map(client: &Client) -> Map {
+    let mut map = Map::new();
+    map.insert("id".into(), (client.id() as i64).into());
+    map.insert("available".into(), client.available().to_f64().into());
+    map.insert("held".into(), client.held().to_f64().into());
+    map.insert("authorized".into(), client.authorized().to_f64().into());
+    map.insert("pending".into(), client.pending().to_f64().into());
+    map.insert("total".into(), client.total().to_f64().into());
+    map.insert("locked".into(), client.locked().into());
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RuleDecision, RulesEngine};
+    use crate::transaction::Transaction;
+    use crate::Client;
+    use std::time::Duration;
+
+    fn evaluate(script: &str) -> RuleDecision {
+        let rules = RulesEngine::from_script(script, Duration::from_millis(50)).unwrap();
+        let t = Transaction::new("deposit", 1, 1, Some(500.0));
+        rules.evaluate(&t, &Client::from_id(1), &[]).unwrap()
+    }
+
+    #[test]
+    fn a_script_without_decision_accepts() {
+        assert_eq!(evaluate("let x = 1;"), RuleDecision::Accept);
+    }
+
+    #[test]
+    fn a_script_can_reject_with_a_reason() {
+        let script = r#"if tx.amount > 100.0 { reject("too large") } else { accept() }"#;
+        assert_eq!(
+            evaluate(script),
+            RuleDecision::Reject("too large".to_string())
+        );
+    }
+
+    #[test]
+    fn rows_without_an_amount_have_zero() {
+        let rules = RulesEngine::from_script(
+            r#"if tx.amount > 100.0 || tx.has_amount { reject("too large") }"#,
+            Duration::from_millis(50),
+        )
+        .unwrap();
+        let t = Transaction::new("dispute", 1, 1, None);
+        assert_eq!(
+            rules.evaluate(&t, &Client::from_id(1), &[]),
+            Ok(RuleDecision::Accept)
+        );
+    }
+
+    #[test]
+    fn a_script_sees_the_client_state() {
+        assert_eq!(
+            evaluate("if client.total == 0.0 { hold() }"),
+            RuleDecision::Hold
+        );
+    }
+
+    #[test]
+    fn a_script_exceeding_its_time_budget_fails() {
+        let rules = RulesEngine::from_script("loop {}", Duration::from_millis(10)).unwrap();
+        let t = Transaction::new("deposit", 1, 1, Some(1.0));
+        assert!(rules.evaluate(&t, &Client::from_id(1), &[]).is_err());
+    }
+}
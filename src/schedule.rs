@@ -0,0 +1,31 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+/// Transaction ids that become due at a point in time of the engine's clock.
+///
+/// Entries are not removed when the transaction is finished early,
+/// so callers have to ignore ids that are no longer pending.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Schedule {
+    due: BinaryHeap<Reverse<(u64, u32)>>,
+}
+
+impl Schedule {
+    pub fn push(&mut self, at: u64, id: u32) {
+        self.due.push(Reverse((at, id)));
+    }
+
+    /// Removes and returns the ids that are due at `now`, earliest first.
+    pub fn pop_due(&mut self, now: u64) -> Vec<u32> {
+        let mut ids = Vec::new();
+        while let Some(Reverse((at, id))) = self.due.peek().copied() {
+            if at > now {
+                break;
+            }
+            self.due.pop();
+            ids.push(id);
+        }
+        ids
+    }
+}
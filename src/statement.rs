@@ -0,0 +1,68 @@
+use crate::amount::Amount;
+use crate::errors::TransactionError;
+use crate::transaction::Transaction;
+use crate::Client;
+use serde::Serialize;
+
+/// One input row of a client's statement with the balances after it was processed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StatementLine {
+    /// The position of the row in the input, starting at 1.
+    pub sequence: u64,
+    pub tx: u32,
+    #[serde(rename = "type")]
+    pub tx_type: String,
+    pub amount: Option<f64>,
+    pub accepted: bool,
+    pub error: Option<String>,
+    pub available: Amount,
+    pub held: Amount,
+    pub authorized: Amount,
+    pub pending: Amount,
+    pub total: Amount,
+    pub locked: bool,
+    /// The stable code of the error, see `TransactionError::code`.
+    pub code: Option<&'static str>,
+}
+
+impl StatementLine {
+    pub fn new(
+        sequence: u64,
+        t: &Transaction,
+        result: &Result<(), TransactionError>,
+        client: &Client,
+    ) -> Self {
+        Self {
+            sequence,
+            tx: t.id(),
+            tx_type: t.raw_tx_type().to_string(),
+            amount: t.amount(),
+            accepted: result.is_ok(),
+            error: result.as_ref().err().map(ToString::to_string),
+            available: client.available(),
+            held: client.held(),
+            authorized: client.authorized(),
+            pending: client.pending(),
+            total: client.total(),
+            locked: client.locked(),
+            code: result.as_ref().err().map(TransactionError::code),
+        }
+    }
+}
+
+/// Narrows a statement down to a transaction id and/or a transaction type.
+#[derive(Debug, Clone, Default)]
+pub struct StatementFilter {
+    pub tx_id: Option<u32>,
+    pub tx_type: Option<String>,
+}
+
+impl StatementFilter {
+    pub fn matches(&self, line: &StatementLine) -> bool {
+        self.tx_id.is_none_or(|id| line.tx == id)
+            && self
+                .tx_type
+                .as_ref()
+                .is_none_or(|tx_type| &line.tx_type == tx_type)
+    }
+}
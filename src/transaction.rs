@@ -1,27 +1,58 @@
-use crate::errors::TransactionError;
-use serde::Deserialize;
+use crate::amount::Amount;
+use crate::errors::{TransactionError, TxState};
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
 use std::{
     fmt::{self, Display},
     str::FromStr,
 };
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum TxType {
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
     Chargeback,
+    Authorize,
+    Capture,
+    Void,
+    PendingDeposit,
+    Settle,
+    Return,
+    Reversal,
+    Refund,
+}
+
+impl TxType {
+    /// The name of the type in the input.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TxType::Deposit => "deposit",
+            TxType::Withdrawal => "withdrawal",
+            TxType::Dispute => "dispute",
+            TxType::Resolve => "resolve",
+            TxType::Chargeback => "chargeback",
+            TxType::Authorize => "authorize",
+            TxType::Capture => "capture",
+            TxType::Void => "void",
+            TxType::PendingDeposit => "pending_deposit",
+            TxType::Settle => "settle",
+            TxType::Return => "return",
+            TxType::Reversal => "reversal",
+            TxType::Refund => "refund",
+        }
+    }
 }
 
 impl Display for TxType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        f.write_str(self.as_str())
     }
 }
 
 impl std::str::FromStr for TxType {
-    type Err = TransactionError;
+    type Err = String;
 
     fn from_str(input: &str) -> Result<TxType, Self::Err> {
         match input {
@@ -30,41 +61,100 @@ impl std::str::FromStr for TxType {
             "dispute" => Ok(TxType::Dispute),
             "resolve" => Ok(TxType::Resolve),
             "chargeback" => Ok(TxType::Chargeback),
-            _ => Err(TransactionError::UnknownTransactionType),
+            "authorize" => Ok(TxType::Authorize),
+            "capture" => Ok(TxType::Capture),
+            "void" => Ok(TxType::Void),
+            "pending_deposit" => Ok(TxType::PendingDeposit),
+            "settle" => Ok(TxType::Settle),
+            "return" => Ok(TxType::Return),
+            "reversal" => Ok(TxType::Reversal),
+            "refund" => Ok(TxType::Refund),
+            _ => Err(format!("unknown transaction type `{}`", input)),
+        }
+    }
+}
+
+/// The type column of a row. Unknown types are kept as they were given,
+/// so they can be reported, known ones need no allocation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RowType {
+    Known(TxType),
+    Unknown(Box<str>),
+}
+
+impl From<&str> for RowType {
+    fn from(input: &str) -> Self {
+        match TxType::from_str(input) {
+            Ok(tx_type) => RowType::Known(tx_type),
+            Err(_) => RowType::Unknown(input.into()),
         }
     }
 }
 
+impl<'de> Deserialize<'de> for RowType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RowTypeVisitor;
+
+        impl Visitor<'_> for RowTypeVisitor {
+            type Value = RowType;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a transaction type")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<RowType, E> {
+                Ok(RowType::from(value))
+            }
+        }
+
+        deserializer.deserialize_str(RowTypeVisitor)
+    }
+}
+
+impl Serialize for RowType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            RowType::Known(tx_type) => serializer.serialize_str(tx_type.as_str()),
+            RowType::Unknown(tx_type) => serializer.serialize_str(tx_type),
+        }
+    }
+}
+
+/// A row of the input.
 // By default, struct field names are deserialized based on the position of
 // a corresponding field in the CSV data's header record.
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct Transaction {
     #[serde(rename = "type")]
-    tx_type: String,
+    tx_type: RowType,
     #[serde(rename = "client")]
     client_id: u16,
     #[serde(rename = "tx")]
     tx_id: u32,
     amount: Option<f64>,
-    #[serde(skip)]
-    disputed: bool,
-    #[serde(skip)]
-    charged_back: bool,
+    /// Seconds since the unix epoch, the column is optional.
+    #[serde(default)]
+    timestamp: Option<u64>,
 }
 
 impl Transaction {
     #[allow(dead_code)]
-    pub fn new(tx_type: String, client_id: u16, tx_id: u32, amount: Option<f64>) -> Self {
+    pub fn new(tx_type: impl AsRef<str>, client_id: u16, tx_id: u32, amount: Option<f64>) -> Self {
         Self {
-            tx_type,
+            tx_type: RowType::from(tx_type.as_ref()),
             client_id,
             tx_id,
             amount,
-            disputed: false,
-            charged_back: false,
+            timestamp: None,
         }
     }
 
+    #[allow(dead_code)]
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
     pub fn id(&self) -> u32 {
         self.tx_id
     }
@@ -75,64 +165,373 @@ impl Transaction {
     }
 
     pub fn tx_type(&self) -> Result<TxType, TransactionError> {
-        TxType::from_str(&self.tx_type)
+        match &self.tx_type {
+            RowType::Known(tx_type) => Ok(*tx_type),
+            RowType::Unknown(tx_type) => Err(TransactionError::UnknownTransactionType {
+                tx_id: self.tx_id,
+                client_id: self.client_id,
+                tx_type: tx_type.to_string(),
+            }),
+        }
+    }
+
+    /// The transaction type as it was given in the input.
+    pub fn raw_tx_type(&self) -> &str {
+        match &self.tx_type {
+            RowType::Known(tx_type) => tx_type.as_str(),
+            RowType::Unknown(tx_type) => tx_type,
+        }
     }
 
     pub fn amount(&self) -> Option<f64> {
         self.amount
     }
 
-    // if the transaction is already under dispute,
-    // this function returns an error.
-    pub fn dispute(&mut self) -> Result<(), TransactionError> {
-        if self.disputed || self.tx_type()? != TxType::Deposit {
-            Err(TransactionError::InvalidDispute)
+    pub fn timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
+}
+
+impl fmt::Display for Transaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Transaction {}: {} for client {} with amount {}",
+            self.id(),
+            self.raw_tx_type(),
+            self.client_id,
+            self.amount.unwrap_or_default()
+        )
+    }
+}
+
+const DISPUTED: u8 = 1;
+const CHARGED_BACK: u8 = 1 << 1;
+const SETTLED: u8 = 1 << 2;
+const RETURNED: u8 = 1 << 3;
+const REVERSED: u8 = 1 << 4;
+const HAS_TIMESTAMP: u8 = 1 << 5;
+
+/// What the handler keeps of a deposit, withdrawal, authorization or pending
+/// deposit, so later rows can refer to it. It has a fixed size and no heap
+/// allocation, the rows referring to it are not kept at all.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct StoredTransaction {
+    /// In ten-thousandths, see `Amount`. Rows carry at most `MAX_AMOUNT`, so it fits.
+    amount: i64,
+    refunded: i64,
+    /// Only meaningful with `HAS_TIMESTAMP`.
+    timestamp: u64,
+    tx_id: u32,
+    client_id: u16,
+    tx_type: TxType,
+    flags: u8,
+}
+
+impl StoredTransaction {
+    pub fn new(t: &Transaction, tx_type: TxType, amount: Amount) -> Self {
+        let mut stored = Self {
+            amount: amount.ten_thousandths() as i64,
+            refunded: 0,
+            timestamp: t.timestamp().unwrap_or_default(),
+            tx_id: t.id(),
+            client_id: t.client_id(),
+            tx_type,
+            flags: 0,
+        };
+        stored.set(HAS_TIMESTAMP, t.timestamp().is_some());
+        stored
+    }
+
+    fn is(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    fn set(&mut self, flag: u8, value: bool) {
+        if value {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.tx_id
+    }
+
+    pub fn client_id(&self) -> u16 {
+        self.client_id
+    }
+
+    pub fn tx_type(&self) -> TxType {
+        self.tx_type
+    }
+
+    pub fn amount(&self) -> Amount {
+        Amount::from_ten_thousandths(self.amount)
+    }
+
+    pub fn timestamp(&self) -> Option<u64> {
+        self.is(HAS_TIMESTAMP).then_some(self.timestamp)
+    }
+
+    /// Deposits and settled pending deposits are credited to the available funds.
+    fn is_credited_deposit(&self) -> bool {
+        match self.tx_type {
+            TxType::Deposit => true,
+            TxType::PendingDeposit => self.is(SETTLED),
+            _ => false,
+        }
+    }
+
+    /// Why a transaction that is not a credited deposit can't be treated as one.
+    fn uncredited_state(&self) -> TxState {
+        if self.pending() {
+            TxState::Pending
         } else {
-            self.disputed = true;
+            TxState::WrongType
+        }
+    }
+
+    /// Why a transaction that is not pending can't be settled or returned.
+    fn not_pending_state(&self) -> TxState {
+        if self.tx_type == TxType::PendingDeposit {
+            TxState::NotPending
+        } else {
+            TxState::WrongType
+        }
+    }
+
+    /// A pending deposit that was neither settled nor returned yet.
+    pub fn pending(&self) -> bool {
+        self.tx_type == TxType::PendingDeposit && !self.is(SETTLED) && !self.is(RETURNED)
+    }
+
+    pub fn settle(&mut self) -> Result<(), TransactionError> {
+        if !self.pending() {
+            Err(TransactionError::InvalidSettle {
+                tx_id: self.tx_id,
+                client_id: self.client_id,
+                state: self.not_pending_state(),
+            })
+        } else {
+            self.set(SETTLED, true);
             Ok(())
         }
     }
 
+    pub fn return_deposit(&mut self) -> Result<(), TransactionError> {
+        if !self.pending() {
+            Err(TransactionError::InvalidReturn {
+                tx_id: self.tx_id,
+                client_id: self.client_id,
+                state: self.not_pending_state(),
+            })
+        } else {
+            self.set(RETURNED, true);
+            Ok(())
+        }
+    }
+
+    /// The part of the amount that was not refunded yet.
+    pub fn remaining_amount(&self) -> Amount {
+        Amount::from_ten_thousandths(self.amount - self.refunded)
+    }
+
+    /// Returns the amount a reversal has to undo, if the transaction can still be reversed.
+    pub fn reversal_amount(&self) -> Result<Amount, TransactionError> {
+        let reversible = self.is_credited_deposit() || self.tx_type == TxType::Withdrawal;
+        let state = if !reversible {
+            Some(self.uncredited_state())
+        } else if self.is(REVERSED) {
+            Some(TxState::Reversed)
+        } else if self.is(DISPUTED) {
+            Some(TxState::Disputed)
+        } else if self.is(CHARGED_BACK) {
+            Some(TxState::ChargedBack)
+        } else {
+            None
+        };
+        match state {
+            Some(state) => Err(TransactionError::InvalidReversal {
+                tx_id: self.tx_id,
+                client_id: self.client_id,
+                state,
+            }),
+            None => Ok(self.remaining_amount()),
+        }
+    }
+
+    pub fn reverse(&mut self) {
+        self.set(REVERSED, true);
+    }
+
+    /// Checks that `amount` can still be refunded from this withdrawal.
+    pub fn check_refund(&self, amount: Amount) -> Result<(), TransactionError> {
+        let state = if self.tx_type != TxType::Withdrawal {
+            Some(TxState::WrongType)
+        } else if self.is(REVERSED) {
+            Some(TxState::Reversed)
+        } else if self.is(DISPUTED) {
+            Some(TxState::Disputed)
+        } else if self.is(CHARGED_BACK) {
+            Some(TxState::ChargedBack)
+        } else {
+            None
+        };
+        if let Some(state) = state {
+            return Err(TransactionError::InvalidRefund {
+                tx_id: self.tx_id,
+                client_id: self.client_id,
+                state,
+            });
+        }
+        let remaining = self.remaining_amount();
+        if amount > remaining {
+            Err(TransactionError::RefundExceedsRemainingAmount {
+                tx_id: self.tx_id,
+                client_id: self.client_id,
+                amount,
+                remaining,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn refund(&mut self, amount: Amount) {
+        self.refunded += amount.ten_thousandths() as i64;
+    }
+
+    #[allow(dead_code)]
+    pub fn reversed(&self) -> bool {
+        self.is(REVERSED)
+    }
+
+    /// Deposits can always be disputed, withdrawals only if `withdrawals` is set.
+    fn is_disputable(&self, withdrawals: bool) -> bool {
+        self.is_credited_deposit() || (withdrawals && self.tx_type == TxType::Withdrawal)
+    }
+
     // if the transaction is already under dispute,
     // this function returns an error.
+    pub fn dispute(&mut self, withdrawals: bool) -> Result<(), TransactionError> {
+        let state = if !self.is_disputable(withdrawals) {
+            Some(self.uncredited_state())
+        } else if self.is(DISPUTED) {
+            Some(TxState::Disputed)
+        } else if self.is(REVERSED) {
+            Some(TxState::Reversed)
+        } else if self.is(CHARGED_BACK) {
+            Some(TxState::ChargedBack)
+        } else {
+            None
+        };
+        match state {
+            Some(state) => Err(TransactionError::InvalidDispute {
+                tx_id: self.tx_id,
+                client_id: self.client_id,
+                state,
+            }),
+            None => {
+                self.set(DISPUTED, true);
+                Ok(())
+            }
+        }
+    }
+
+    // if the transaction is not under dispute,
+    // this function returns an error.
     pub fn resolve(&mut self) -> Result<(), TransactionError> {
-        if !self.disputed || self.tx_type()? != TxType::Deposit {
-            Err(TransactionError::InvalidResolve)
+        if !self.is(DISPUTED) {
+            Err(TransactionError::InvalidResolve {
+                tx_id: self.tx_id,
+                client_id: self.client_id,
+                state: self.undisputed_state(),
+            })
         } else {
-            self.disputed = false;
+            self.set(DISPUTED, false);
             Ok(())
         }
     }
 
+    /// Ends the dispute by charging the transaction back.
     pub fn chargeback(&mut self) -> Result<(), TransactionError> {
-        if self.charged_back || self.tx_type()? != TxType::Deposit {
-            Err(TransactionError::InvalidChargeback)
+        if !self.is(DISPUTED) {
+            Err(TransactionError::InvalidChargeback {
+                tx_id: self.tx_id,
+                client_id: self.client_id,
+                state: self.undisputed_state(),
+            })
         } else {
-            self.charged_back = true;
+            self.set(DISPUTED, false);
+            self.set(CHARGED_BACK, true);
             Ok(())
         }
     }
 
+    /// Why a transaction can't be resolved or charged back.
+    fn undisputed_state(&self) -> TxState {
+        if self.is(CHARGED_BACK) {
+            TxState::ChargedBack
+        } else {
+            TxState::NotDisputed
+        }
+    }
+
     #[allow(dead_code)]
     pub fn charged_back(&self) -> bool {
-        self.charged_back
+        self.is(CHARGED_BACK)
     }
 
     #[allow(dead_code)]
     pub fn disputed(&self) -> bool {
-        self.disputed
+        self.is(DISPUTED)
     }
 }
 
-impl fmt::Display for Transaction {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "Transaction {}: {} for client {} with amount {}",
-            self.id(),
-            self.tx_type,
-            self.client_id,
-            self.amount.unwrap_or_default()
-        )
+#[cfg(test)]
+mod tests {
+    use super::{RowType, StoredTransaction, Transaction, TxType};
+    use crate::amount::Amount;
+    use crate::errors::{TransactionError, TxState};
+
+    #[test]
+    fn rows_are_deserialized_into_typed_records() {
+        let data = "type,client,tx,amount\ndeposit,1,1,1.5\nteleport,2,2,\n";
+        let mut reader = csv::Reader::from_reader(data.as_bytes());
+        let rows: Vec<Transaction> = reader.deserialize().map(Result::unwrap).collect();
+
+        assert_eq!(rows[0].tx_type, RowType::Known(TxType::Deposit));
+        assert_eq!(rows[0].tx_type(), Ok(TxType::Deposit));
+        assert_eq!(rows[1].raw_tx_type(), "teleport");
+        assert_eq!(
+            rows[1].tx_type(),
+            Err(TransactionError::UnknownTransactionType {
+                tx_id: 2,
+                client_id: 2,
+                tx_type: "teleport".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn stored_transactions_are_small_and_keep_their_state() {
+        assert_eq!(std::mem::size_of::<StoredTransaction>(), 32);
+
+        let row = Transaction::new("deposit", 1, 7, Some(2.0)).with_timestamp(0);
+        let mut stored = StoredTransaction::new(&row, TxType::Deposit, Amount::from_f64(2.0));
+        assert_eq!(stored.timestamp(), Some(0));
+        stored.dispute(false).unwrap();
+        assert!(stored.disputed());
+        stored.chargeback().unwrap();
+        assert!(stored.charged_back() && !stored.disputed());
+        assert!(matches!(
+            stored.dispute(false),
+            Err(TransactionError::InvalidDispute {
+                state: TxState::ChargedBack,
+                ..
+            })
+        ));
     }
 }
@@ -0,0 +1,337 @@
+use crate::errors::TransactionError;
+use crate::transaction::{StoredTransaction, Transaction, TxType};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Write};
+
+/// Amounts are given with at most this many decimal places.
+pub const MAX_DECIMAL_PLACES: i32 = 4;
+
+/// The largest amount a row may have, balances are summed up exactly from them.
+pub const MAX_AMOUNT: f64 = 100_000_000_000.0;
+
+/// What validators may look at besides the row itself.
+pub struct ValidationContext<'a> {
+    transactions: &'a HashMap<u32, StoredTransaction>,
+    dropped_ids: Option<&'a HashSet<u32>>,
+}
+
+impl<'a> ValidationContext<'a> {
+    pub fn new(transactions: &'a HashMap<u32, StoredTransaction>) -> Self {
+        Self {
+            transactions,
+            dropped_ids: None,
+        }
+    }
+
+    /// Ids of transactions that were accepted but are no longer kept.
+    pub fn with_dropped_ids(mut self, dropped_ids: &'a HashSet<u32>) -> Self {
+        self.dropped_ids = Some(dropped_ids);
+        self
+    }
+
+    /// A transaction that was accepted earlier.
+    pub fn transaction(&self, id: u32) -> Option<&StoredTransaction> {
+        self.transactions.get(&id)
+    }
+
+    /// Whether a transaction with this id was accepted earlier, even if it is no longer kept.
+    pub fn is_used(&self, id: u32) -> bool {
+        self.transactions.contains_key(&id)
+            || self
+                .dropped_ids
+                .is_some_and(|dropped| dropped.contains(&id))
+    }
+}
+
+/// Checks a row before it changes any state. Rows that fail a validator are rejected.
+pub trait Validator {
+    fn validate(
+        &self,
+        t: &Transaction,
+        context: &ValidationContext,
+    ) -> Result<(), TransactionError>;
+}
+
+/// Rejects rows whose type is not one of `TxType`.
+pub struct KnownType;
+
+impl Validator for KnownType {
+    fn validate(&self, t: &Transaction, _: &ValidationContext) -> Result<(), TransactionError> {
+        t.tx_type().map(|_| ())
+    }
+}
+
+/// Rows that move new money need an amount, rows referring to an earlier transaction or
+/// authorization hold must not have one.
+pub struct AmountPresence;
+
+impl Validator for AmountPresence {
+    fn validate(&self, t: &Transaction, _: &ValidationContext) -> Result<(), TransactionError> {
+        match (t.tx_type()?, t.amount()) {
+            (
+                TxType::Deposit
+                | TxType::Withdrawal
+                | TxType::Authorize
+                | TxType::PendingDeposit
+                | TxType::Refund,
+                None,
+            ) => Err(TransactionError::MissingAmount {
+                tx_id: t.id(),
+                client_id: t.client_id(),
+            }),
+            (
+                TxType::Dispute
+                | TxType::Resolve
+                | TxType::Chargeback
+                | TxType::Capture
+                | TxType::Void
+                | TxType::Settle
+                | TxType::Return
+                | TxType::Reversal,
+                Some(amount),
+            ) => Err(TransactionError::UnexpectedAmount {
+                tx_id: t.id(),
+                client_id: t.client_id(),
+                amount,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Amounts have to be greater than zero and at most `MAX_AMOUNT`.
+pub struct PositiveAmount;
+
+impl Validator for PositiveAmount {
+    fn validate(&self, t: &Transaction, _: &ValidationContext) -> Result<(), TransactionError> {
+        match t.amount() {
+            Some(amount) if !(amount > 0.0 && amount <= MAX_AMOUNT) => {
+                Err(TransactionError::InvalidAmount {
+                    tx_id: t.id(),
+                    client_id: t.client_id(),
+                    amount,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Collects formatted text in a fixed buffer, so checking a row doesn't allocate.
+#[derive(Default)]
+struct FixedBuffer {
+    bytes: [u8; 32],
+    len: usize,
+}
+
+impl fmt::Write for FixedBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        let target = self.bytes.get_mut(self.len..end).ok_or(fmt::Error)?;
+        target.copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Amounts may have at most `MAX_DECIMAL_PLACES` decimal places.
+pub struct DecimalPlaces;
+
+impl Validator for DecimalPlaces {
+    fn validate(&self, t: &Transaction, _: &ValidationContext) -> Result<(), TransactionError> {
+        let amount = match t.amount() {
+            Some(amount) => amount,
+            None => return Ok(()),
+        };
+        // f64 can't represent most decimals exactly, but an amount that was given with
+        // few enough places is the closest f64 to itself rounded to them, at any size
+        let mut rounded = FixedBuffer::default();
+        let places = MAX_DECIMAL_PLACES as usize;
+        if write!(rounded, "{:.*}", places, amount).is_err() {
+            // too large for f64 to tell decimal places apart
+            return Ok(());
+        }
+        let rounded = std::str::from_utf8(&rounded.bytes[..rounded.len])
+            .ok()
+            .and_then(|rounded| rounded.parse::<f64>().ok());
+        if rounded != Some(amount) {
+            return Err(TransactionError::TooManyDecimalPlaces {
+                tx_id: t.id(),
+                client_id: t.client_id(),
+                amount,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Rows that create a transaction need an id that has not been used before.
+pub struct UniqueId;
+
+impl Validator for UniqueId {
+    fn validate(
+        &self,
+        t: &Transaction,
+        context: &ValidationContext,
+    ) -> Result<(), TransactionError> {
+        let creates_transaction = matches!(
+            t.tx_type()?,
+            TxType::Deposit | TxType::Withdrawal | TxType::Authorize | TxType::PendingDeposit
+        );
+        if creates_transaction && context.is_used(t.id()) {
+            return Err(TransactionError::TransactionExistsAlready {
+                tx_id: t.id(),
+                client_id: t.client_id(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Runs validators in order and stops at the first one that rejects the row.
+pub struct ValidationPipeline {
+    validators: Vec<Box<dyn Validator>>,
+}
+
+impl Default for ValidationPipeline {
+    /// The built-in validators.
+    fn default() -> Self {
+        Self {
+            validators: vec![
+                Box::new(KnownType),
+                Box::new(AmountPresence),
+                Box::new(PositiveAmount),
+                Box::new(DecimalPlaces),
+                Box::new(UniqueId),
+            ],
+        }
+    }
+}
+
+impl ValidationPipeline {
+    /// Runs `validator` after the ones added before.
+    pub fn push(&mut self, validator: Box<dyn Validator>) {
+        self.validators.push(validator);
+    }
+
+    pub fn validate(
+        &self,
+        t: &Transaction,
+        context: &ValidationContext,
+    ) -> Result<(), TransactionError> {
+        self.validators
+            .iter()
+            .try_for_each(|validator| validator.validate(t, context))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ValidationContext, ValidationPipeline};
+    use crate::amount::Amount;
+    use crate::errors::TransactionError;
+    use crate::transaction::{StoredTransaction, Transaction, TxType};
+    use std::collections::HashMap;
+
+    fn validate(tx_type: &str, amount: Option<f64>) -> Result<(), TransactionError> {
+        let transactions = HashMap::new();
+        let t = Transaction::new(tx_type, 1, 1, amount);
+        ValidationPipeline::default().validate(&t, &ValidationContext::new(&transactions))
+    }
+
+    #[test]
+    fn built_in_validators_reject_malformed_rows() {
+        assert!(validate("deposit", Some(1.0001)).is_ok());
+        assert!(validate("dispute", None).is_ok());
+        assert!(matches!(
+            validate("deposit", None),
+            Err(TransactionError::MissingAmount { tx_id: 1, .. })
+        ));
+        assert!(matches!(
+            validate("withdrawal", Some(-1.0)),
+            Err(TransactionError::InvalidAmount { .. })
+        ));
+        assert!(matches!(
+            validate("deposit", Some(0.0)),
+            Err(TransactionError::InvalidAmount { .. })
+        ));
+        assert!(matches!(
+            validate("deposit", Some(2.00001)),
+            Err(TransactionError::TooManyDecimalPlaces { .. })
+        ));
+        assert!(matches!(
+            validate("deposit", Some(12345678.12345)),
+            Err(TransactionError::TooManyDecimalPlaces { .. })
+        ));
+        for tx_type in [
+            "dispute",
+            "resolve",
+            "chargeback",
+            "capture",
+            "void",
+            "settle",
+            "return",
+            "reversal",
+        ] {
+            assert!(
+                matches!(
+                    validate(tx_type, Some(1.0)),
+                    Err(TransactionError::UnexpectedAmount { .. })
+                ),
+                "{}",
+                tx_type
+            );
+        }
+        assert!(matches!(
+            validate("transfer", Some(1.0)),
+            Err(TransactionError::UnknownTransactionType { .. })
+        ));
+    }
+
+    #[test]
+    fn large_amounts_with_four_decimal_places_are_valid() {
+        for amount in ["87455328.6219", "35746282.3748", "24951916.4856", "0.0001"] {
+            assert!(
+                validate("deposit", amount.parse().ok()).is_ok(),
+                "{}",
+                amount
+            );
+        }
+        assert!(validate("deposit", Some(100_000_000_000.0)).is_ok());
+        assert!(matches!(
+            validate("deposit", Some(1e300)),
+            Err(TransactionError::InvalidAmount { .. })
+        ));
+        let mut seed = 7u64;
+        for _ in 0..10_000 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let amount = format!("{}.{:04}", (seed >> 20) % 100_000_000_000, seed % 10_000);
+            assert!(
+                validate("deposit", amount.parse().ok()).is_ok(),
+                "{}",
+                amount
+            );
+        }
+    }
+
+    #[test]
+    fn ids_of_accepted_transactions_can_not_be_reused() {
+        let mut transactions = HashMap::new();
+        let t = Transaction::new("deposit", 1, 1, Some(1.0));
+        transactions.insert(
+            1,
+            StoredTransaction::new(&t, TxType::Deposit, Amount::from_f64(1.0)),
+        );
+        let context = ValidationContext::new(&transactions);
+        let pipeline = ValidationPipeline::default();
+
+        assert!(matches!(
+            pipeline.validate(&t, &context),
+            Err(TransactionError::TransactionExistsAlready { .. })
+        ));
+        let dispute = Transaction::new("dispute", 1, 1, None);
+        assert!(pipeline.validate(&dispute, &context).is_ok());
+    }
+}
@@ -0,0 +1,375 @@
+use crate::amount::Amount;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, Write};
+
+/// Disputes and duplicate ids refer to one of this many recent deposits.
+const RECENT_DEPOSITS: usize = 4096;
+
+/// Largest generated amount in ten thousandths.
+const MAX_AMOUNT: u64 = 1_000_000;
+
+/// The shape of a generated transactions file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkloadConfig {
+    pub clients: u16,
+    /// Rows below the header.
+    pub transactions: u64,
+    /// Share of deposits and withdrawals that are withdrawals.
+    pub withdrawal_ratio: f64,
+    /// Share of rows that dispute a recent deposit.
+    pub dispute_rate: f64,
+    /// Share of rows that resolve an open dispute.
+    pub resolve_rate: f64,
+    /// Share of rows that charge back an open dispute.
+    pub chargeback_rate: f64,
+    /// Share of rows the validation rejects.
+    pub invalid_rate: f64,
+    /// Share of rows with padded fields.
+    pub whitespace_rate: f64,
+    pub seed: u64,
+}
+
+impl Default for WorkloadConfig {
+    fn default() -> Self {
+        Self {
+            clients: 1000,
+            transactions: 100_000,
+            withdrawal_ratio: 0.4,
+            dispute_rate: 0.01,
+            resolve_rate: 0.005,
+            chargeback_rate: 0.002,
+            invalid_rate: 0.001,
+            whitespace_rate: 0.1,
+            seed: 0,
+        }
+    }
+}
+
+/// SplitMix64, small and stable, so a seed always yields the same file.
+#[derive(Debug, Clone)]
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`.
+    fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        self.unit() < p
+    }
+
+    /// Uniform in `[0, n)`, `n` has to be positive.
+    fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+}
+
+/// A client account as the engine with its default configuration would keep it.
+#[derive(Debug, Clone, Copy, Default)]
+struct ModelAccount {
+    available: Amount,
+    held: Amount,
+    locked: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ModelDeposit {
+    client: u16,
+    amount: Amount,
+    disputed: bool,
+    charged_back: bool,
+}
+
+/// A row of the expected accounts, in the columns of the engine's output.
+#[derive(Debug, Serialize)]
+struct ExpectedAccount {
+    client: u16,
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: bool,
+    authorized: Amount,
+    pending: Amount,
+}
+
+/// Writes random transaction rows and keeps track of what the engine will make of them.
+///
+/// The expected accounts are computed by replaying the default rules in the same
+/// `Amount` operations as the engine, so they can be compared to its output byte by byte.
+pub struct Generator {
+    config: WorkloadConfig,
+    rng: Rng,
+    next_tx: u32,
+    accounts: BTreeMap<u16, ModelAccount>,
+    deposits: HashMap<u32, ModelDeposit>,
+    recent: VecDeque<u32>,
+    open_disputes: Vec<u32>,
+}
+
+impl Generator {
+    pub fn new(config: WorkloadConfig) -> Self {
+        Self {
+            rng: Rng(config.seed),
+            config,
+            next_tx: 1,
+            accounts: BTreeMap::new(),
+            deposits: HashMap::new(),
+            recent: VecDeque::with_capacity(RECENT_DEPOSITS),
+            open_disputes: Vec::new(),
+        }
+    }
+
+    /// Writes the header and all rows of the configured workload.
+    pub fn write_csv<W: Write>(&mut self, mut out: W) -> io::Result<()> {
+        writeln!(out, "type, client, tx, amount")?;
+        for _ in 0..self.config.transactions {
+            writeln!(out, "{}", self.next_row())?;
+        }
+        out.flush()
+    }
+
+    /// Writes the accounts the engine should print for the rows generated so far,
+    /// ordered by client id.
+    pub fn write_expected_accounts<W: Write>(&self, out: W) -> Result<(), csv::Error> {
+        let mut wtr = csv::Writer::from_writer(out);
+        for (client, account) in &self.accounts {
+            wtr.serialize(ExpectedAccount {
+                client: *client,
+                available: account.available,
+                held: account.held,
+                total: account.available + account.held,
+                locked: account.locked,
+                authorized: Amount::ZERO,
+                pending: Amount::ZERO,
+            })?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// The next row, without a line break.
+    pub fn next_row(&mut self) -> String {
+        let roll = self.rng.unit();
+        let config = &self.config;
+        let invalid = config.invalid_rate;
+        let dispute = invalid + config.dispute_rate;
+        let resolve = dispute + config.resolve_rate;
+        let chargeback = resolve + config.chargeback_rate;
+
+        let fields = if roll < invalid {
+            self.invalid_row()
+        } else if roll < dispute && !self.recent.is_empty() {
+            let tx = self.recent[self.rng.below(self.recent.len() as u64) as usize];
+            self.dispute(tx)
+        } else if roll < chargeback && !self.open_disputes.is_empty() {
+            let index = self.rng.below(self.open_disputes.len() as u64) as usize;
+            let tx = self.open_disputes.swap_remove(index);
+            self.settle(tx, roll >= resolve)
+        } else {
+            self.transfer()
+        };
+        self.format_row(fields)
+    }
+
+    fn random_client(&mut self) -> u16 {
+        self.rng.below(u64::from(self.config.clients.max(1))) as u16 + 1
+    }
+
+    fn random_amount(&mut self) -> String {
+        let amount = self.rng.below(MAX_AMOUNT) + 1;
+        format!("{}.{:04}", amount / 10_000, amount % 10_000)
+    }
+
+    fn transfer(&mut self) -> (&'static str, u16, u32, String) {
+        let client = self.random_client();
+        let tx = self.next_tx;
+        self.next_tx += 1;
+        let withdrawal = self.rng.chance(self.config.withdrawal_ratio);
+        let amount = self.random_amount();
+        let value = Amount::from_f64(amount.parse().unwrap_or_default());
+
+        let account = self.accounts.entry(client).or_default();
+        if account.locked || (withdrawal && value > account.available) {
+            return (tx_type(withdrawal), client, tx, amount);
+        }
+        if withdrawal {
+            account.available -= value;
+        } else {
+            account.available += value;
+            self.remember_deposit(tx, client, value);
+        }
+        (tx_type(withdrawal), client, tx, amount)
+    }
+
+    fn remember_deposit(&mut self, tx: u32, client: u16, amount: Amount) {
+        let deposit = ModelDeposit {
+            client,
+            amount,
+            disputed: false,
+            charged_back: false,
+        };
+        self.deposits.insert(tx, deposit);
+        if self.recent.len() == RECENT_DEPOSITS {
+            if let Some(old) = self.recent.pop_front() {
+                // disputed deposits are still needed to settle them
+                if self.deposits.get(&old).is_some_and(|d| !d.disputed) {
+                    self.deposits.remove(&old);
+                }
+            }
+        }
+        self.recent.push_back(tx);
+    }
+
+    fn dispute(&mut self, tx: u32) -> (&'static str, u16, u32, String) {
+        // recent deposits are always known
+        let deposit = self.deposits.get(&tx).copied();
+        let client = deposit.map_or(0, |deposit| deposit.client);
+        if let Some(deposit) = deposit.filter(|d| !d.disputed && !d.charged_back) {
+            let account = self.accounts.entry(client).or_default();
+            if deposit.amount <= account.available {
+                account.available -= deposit.amount;
+                account.held += deposit.amount;
+                if let Some(stored) = self.deposits.get_mut(&tx) {
+                    stored.disputed = true;
+                }
+                self.open_disputes.push(tx);
+            }
+        }
+        ("dispute", client, tx, String::new())
+    }
+
+    fn settle(&mut self, tx: u32, chargeback: bool) -> (&'static str, u16, u32, String) {
+        // disputed deposits are never forgotten
+        let deposit = match self.deposits.get_mut(&tx) {
+            Some(deposit) => deposit,
+            None => return self.transfer(),
+        };
+        let account = self.accounts.entry(deposit.client).or_default();
+        let tx_type = if chargeback { "chargeback" } else { "resolve" };
+        deposit.disputed = false;
+        account.held -= deposit.amount;
+        if chargeback {
+            // the default risk policy locks on the first chargeback
+            deposit.charged_back = true;
+            account.locked = true;
+        } else {
+            account.available += deposit.amount;
+        }
+        (tx_type, deposit.client, tx, String::new())
+    }
+
+    /// A row the validation rejects before it creates the client.
+    fn invalid_row(&mut self) -> (&'static str, u16, u32, String) {
+        let client = self.random_client();
+        let tx = self.next_tx;
+        match self.rng.below(5) {
+            0 => ("transfer", client, tx, self.random_amount()),
+            1 => ("deposit", client, tx, String::new()),
+            2 => (
+                "withdrawal",
+                client,
+                tx,
+                format!("-{}", self.random_amount()),
+            ),
+            3 => ("deposit", client, tx, format!("{}1", self.random_amount())),
+            _ => match self.recent.back() {
+                Some(&duplicate) => ("deposit", client, duplicate, self.random_amount()),
+                None => ("transfer", client, tx, self.random_amount()),
+            },
+        }
+    }
+
+    fn format_row(&mut self, (tx_type, client, tx, amount): (&str, u16, u32, String)) -> String {
+        if !self.rng.chance(self.config.whitespace_rate) {
+            return format!("{},{},{},{}", tx_type, client, tx, amount);
+        }
+        let mut pad = || " ".repeat(self.rng.below(4) as usize + 1);
+        format!(
+            "{},{}{},{}{},{}{}",
+            tx_type,
+            pad(),
+            client,
+            pad(),
+            tx,
+            pad(),
+            amount
+        )
+    }
+}
+
+fn tx_type(withdrawal: bool) -> &'static str {
+    if withdrawal {
+        "withdrawal"
+    } else {
+        "deposit"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Generator, WorkloadConfig};
+    use crate::config::InvalidRows;
+    use crate::input::parse_transactions;
+    use crate::ClientTransactionHandler;
+
+    fn config(seed: u64) -> WorkloadConfig {
+        WorkloadConfig {
+            clients: 50,
+            transactions: 20_000,
+            dispute_rate: 0.05,
+            resolve_rate: 0.02,
+            chargeback_rate: 0.01,
+            invalid_rate: 0.02,
+            seed,
+            ..WorkloadConfig::default()
+        }
+    }
+
+    fn generate(config: WorkloadConfig) -> (Vec<u8>, Vec<u8>) {
+        let mut generator = Generator::new(config);
+        let mut rows = Vec::new();
+        generator.write_csv(&mut rows).unwrap();
+        let mut expected = Vec::new();
+        generator.write_expected_accounts(&mut expected).unwrap();
+        (rows, expected)
+    }
+
+    #[test]
+    fn the_same_seed_generates_the_same_file() {
+        assert_eq!(generate(config(7)), generate(config(7)));
+        assert_ne!(generate(config(7)).0, generate(config(8)).0);
+    }
+
+    #[test]
+    fn the_expected_accounts_match_the_engine() {
+        let (rows, expected) = generate(config(3));
+        let mut handler = ClientTransactionHandler::new();
+        parse_transactions(
+            rows.as_slice(),
+            "generated",
+            InvalidRows::Fail,
+            &mut handler,
+        )
+        .unwrap();
+
+        let mut clients: Vec<_> = handler.clients().values().collect();
+        clients.sort_by_key(|client| client.id());
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        for client in clients {
+            wtr.serialize(client).unwrap();
+        }
+        let actual = wtr.into_inner().unwrap();
+        assert_eq!(String::from_utf8(actual), String::from_utf8(expected));
+        assert!(handler.clients().values().any(|client| client.locked()));
+    }
+}
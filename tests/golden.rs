@@ -0,0 +1,189 @@
+//! Runs the engine binary on every scenario in `tests/scenarios` and compares its
+//! accounts and rejected rows with the golden files of the scenario:
+//! - `input.csv` the transactions, or `inputs/` with several, possibly compressed, parts
+//! - `config.toml` the configuration, optional
+//! - `expected_accounts.csv` the accounts ordered by client
+//! - `expected_rejections.csv` the rejected rows in input order
+//!
+//! `UPDATE_GOLDEN=1 cargo test --test golden` rewrites the golden files from the
+//! current output, after a change of behaviour that is intended.
+
+use jellyfish_engine::config::ENV_PREFIX;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+const ACCOUNTS: &str = "expected_accounts.csv";
+const REJECTIONS: &str = "expected_rejections.csv";
+
+/// The accounts and rejections of one run of the engine.
+struct Output {
+    accounts: String,
+    rejections: String,
+}
+
+fn run_scenario(dir: &Path) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_jellyfish-engine"));
+    if dir.join("input.csv").exists() {
+        command.arg(dir.join("input.csv"));
+    } else {
+        // as a pattern, for the engine to expand and order
+        command.arg(dir.join("inputs").join("part-*"));
+    }
+    if dir.join("config.toml").exists() {
+        command.arg("--config").arg(dir.join("config.toml"));
+    }
+    // the scenario's config only, and the output in the format the runner reads
+    for (name, _) in std::env::vars().filter(|(name, _)| name.starts_with(ENV_PREFIX)) {
+        command.env_remove(name);
+    }
+    command
+        .env("RUST_LOG", "error")
+        .env("JELLYFISH_OUTPUT_FORMAT", "csv")
+        .env("JELLYFISH_LOGGING_FORMAT", "json")
+        .env("JELLYFISH_LOGGING_LEVEL", "error");
+
+    let output = command.output().expect("the engine binary can be run");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        output.status.success(),
+        "{} failed: {}",
+        dir.display(),
+        stderr
+    );
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines = stdout.lines();
+    let header = lines.next().unwrap_or_default();
+    let mut rows: Vec<&str> = lines.collect();
+    rows.sort_by_key(|row| leading_number(row));
+    let accounts = std::iter::once(header)
+        .chain(rows)
+        .map(|line| format!("{}\n", line))
+        .collect();
+
+    Output {
+        accounts,
+        rejections: rejections(&stderr),
+    }
+}
+
+fn leading_number(row: &str) -> u64 {
+    row.split(',')
+        .next()
+        .and_then(|field| field.trim().parse().ok())
+        .unwrap_or(u64::MAX)
+}
+
+/// The rejected rows of the json log as csv, ordered by file and line, files in the order
+/// they were read. Messages are left out, they are for people and may change, the codes are stable.
+fn rejections(log: &str) -> String {
+    let mut files: Vec<String> = Vec::new();
+    let mut rows: Vec<((usize, u64), String)> = log
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter(|record| record.get("code").is_some())
+        .map(|record| {
+            let field = |name: &str| match &record[name] {
+                Value::Null => String::new(),
+                Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+            let file = Path::new(record["file"].as_str().unwrap_or_default())
+                .file_name()
+                .map_or(String::new(), |name| name.to_string_lossy().into_owned());
+            let index = files
+                .iter()
+                .position(|known| *known == file)
+                .unwrap_or_else(|| {
+                    files.push(file.clone());
+                    files.len() - 1
+                });
+            let line = record["line"].as_u64().unwrap_or_default();
+            let row = [
+                file,
+                line.to_string(),
+                field("tx"),
+                field("client"),
+                field("tx_type"),
+                field("code"),
+            ]
+            .join(",");
+            ((index, line), row)
+        })
+        .collect();
+    rows.sort_by_key(|(position, _)| *position);
+    std::iter::once("file,line,tx,client,type,code".to_string())
+        .chain(rows.into_iter().map(|(_, row)| row))
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
+
+/// A line diff of `expected` and `actual`, `-` for missing and `+` for unexpected lines.
+fn diff(expected: &str, actual: &str) -> String {
+    let (old, new): (Vec<&str>, Vec<&str>) = (expected.lines().collect(), actual.lines().collect());
+    // longest common subsequence, the files are small
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j, mut out) = (0, 0, String::new());
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            out.push_str(&format!("  {}\n", old[i]));
+            (i, j) = (i + 1, j + 1);
+        } else if j < new.len() && (i == old.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            out.push_str(&format!("+ {}\n", new[j]));
+            j += 1;
+        } else {
+            out.push_str(&format!("- {}\n", old[i]));
+            i += 1;
+        }
+    }
+    out
+}
+
+#[test]
+fn scenarios_match_their_golden_files() {
+    let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/scenarios");
+    let mut scenarios: Vec<_> = fs::read_dir(&root)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_dir())
+        .collect();
+    scenarios.sort();
+    assert!(!scenarios.is_empty());
+
+    let mut failures = Vec::new();
+    for dir in &scenarios {
+        let output = run_scenario(dir);
+        for (file, actual) in [
+            (ACCOUNTS, &output.accounts),
+            (REJECTIONS, &output.rejections),
+        ] {
+            let path = dir.join(file);
+            if update {
+                fs::write(&path, actual).unwrap();
+                continue;
+            }
+            let expected = fs::read_to_string(&path).unwrap_or_default();
+            if &expected != actual {
+                failures.push(format!("{}:\n{}", path.display(), diff(&expected, actual)));
+            }
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "{} golden files differ, rerun with UPDATE_GOLDEN=1 if the change is intended\n\n{}",
+        failures.len(),
+        failures.join("\n")
+    );
+}
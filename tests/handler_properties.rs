@@ -0,0 +1,203 @@
+//! Random transaction sequences against the handler. The invariants are checked
+//! after every row and the balances are compared to a small reference model.
+
+use jellyfish_engine::amount::Amount;
+use jellyfish_engine::transaction::{Transaction, TxType};
+use jellyfish_engine::ClientTransactionHandler;
+use proptest::prelude::*;
+use std::collections::{HashMap, HashSet};
+
+/// Amounts are generated in ten-thousandths, the smallest unit a row can have,
+/// so the model can use integers.
+const UNITS: f64 = 10_000.0;
+
+#[derive(Debug, Clone, Copy)]
+enum Row {
+    Deposit { client: u16, tx: u32, amount: i64 },
+    Withdrawal { client: u16, tx: u32, amount: i64 },
+    Dispute { client: u16, tx: u32 },
+    Resolve { client: u16, tx: u32 },
+    Chargeback { client: u16, tx: u32 },
+}
+
+impl Row {
+    fn client(&self) -> u16 {
+        match *self {
+            Row::Deposit { client, .. }
+            | Row::Withdrawal { client, .. }
+            | Row::Dispute { client, .. }
+            | Row::Resolve { client, .. }
+            | Row::Chargeback { client, .. } => client,
+        }
+    }
+
+    fn transaction(&self) -> Transaction {
+        let new = |tx_type: TxType, client, tx, amount: Option<i64>| {
+            let tx_type = tx_type.to_string().to_ascii_lowercase();
+            Transaction::new(
+                tx_type,
+                client,
+                tx,
+                amount.map(|amount| amount as f64 / UNITS),
+            )
+        };
+        match *self {
+            Row::Deposit { client, tx, amount } => new(TxType::Deposit, client, tx, Some(amount)),
+            Row::Withdrawal { client, tx, amount } => {
+                new(TxType::Withdrawal, client, tx, Some(amount))
+            }
+            Row::Dispute { client, tx } => new(TxType::Dispute, client, tx, None),
+            Row::Resolve { client, tx } => new(TxType::Resolve, client, tx, None),
+            Row::Chargeback { client, tx } => new(TxType::Chargeback, client, tx, None),
+        }
+    }
+}
+
+/// Few clients and ids, so rows often refer to earlier ones and collide.
+fn row() -> impl Strategy<Value = Row> {
+    let client = 1u16..4;
+    let tx = 1u32..24;
+    let amount = 1i64..1_000_000;
+    prop_oneof![
+        3 => (client.clone(), tx.clone(), amount.clone())
+            .prop_map(|(client, tx, amount)| Row::Deposit { client, tx, amount }),
+        2 => (client.clone(), tx.clone(), amount)
+            .prop_map(|(client, tx, amount)| Row::Withdrawal { client, tx, amount }),
+        2 => (client.clone(), tx.clone()).prop_map(|(client, tx)| Row::Dispute { client, tx }),
+        1 => (client.clone(), tx.clone()).prop_map(|(client, tx)| Row::Resolve { client, tx }),
+        1 => (client, tx).prop_map(|(client, tx)| Row::Chargeback { client, tx }),
+    ]
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct ModelClient {
+    available: i64,
+    held: i64,
+    locked: bool,
+}
+
+#[derive(Debug)]
+struct ModelTransaction {
+    client: u16,
+    amount: i64,
+    deposit: bool,
+    disputed: bool,
+    charged_back: bool,
+}
+
+/// What the engine does with its default configuration, in as few lines as possible.
+#[derive(Debug, Default)]
+struct Model {
+    clients: HashMap<u16, ModelClient>,
+    transactions: HashMap<u32, ModelTransaction>,
+}
+
+impl Model {
+    /// Applies `row` and tells whether it was accepted.
+    fn apply(&mut self, row: Row) -> bool {
+        match row {
+            Row::Deposit { client, tx, amount } | Row::Withdrawal { client, tx, amount } => {
+                let deposit = matches!(row, Row::Deposit { .. });
+                // duplicate ids are rejected before the client is created
+                if self.transactions.contains_key(&tx) {
+                    return false;
+                }
+                let account = self.clients.entry(client).or_default();
+                if account.locked || (!deposit && amount > account.available) {
+                    return false;
+                }
+                account.available += if deposit { amount } else { -amount };
+                self.transactions.insert(
+                    tx,
+                    ModelTransaction {
+                        client,
+                        amount,
+                        deposit,
+                        disputed: false,
+                        charged_back: false,
+                    },
+                );
+                true
+            }
+            Row::Dispute { client, tx } => {
+                let t = match self.transactions.get_mut(&tx) {
+                    Some(t)
+                        if t.client == client && t.deposit && !t.disputed && !t.charged_back =>
+                    {
+                        t
+                    }
+                    _ => return false,
+                };
+                let account = self.clients.entry(t.client).or_default();
+                if t.amount > account.available {
+                    return false;
+                }
+                account.available -= t.amount;
+                account.held += t.amount;
+                t.disputed = true;
+                true
+            }
+            Row::Resolve { client, tx } | Row::Chargeback { client, tx } => {
+                let t = match self.transactions.get_mut(&tx) {
+                    Some(t) if t.client == client && t.disputed => t,
+                    _ => return false,
+                };
+                let account = self.clients.entry(t.client).or_default();
+                account.held -= t.amount;
+                t.disputed = false;
+                if matches!(row, Row::Resolve { .. }) {
+                    account.available += t.amount;
+                } else {
+                    // the default risk policy locks on the first chargeback
+                    account.locked = true;
+                    t.charged_back = true;
+                }
+                true
+            }
+        }
+    }
+}
+
+proptest! {
+    #[test]
+    fn balances_stay_consistent_after_every_row(rows in prop::collection::vec(row(), 1..64)) {
+        let mut handler = ClientTransactionHandler::new();
+        let mut charged_back = HashSet::new();
+        for row in rows {
+            let before = handler.clients().get(&row.client()).cloned();
+            let accepted = handler.add_transaction(row.transaction()).is_ok();
+
+            for client in handler.clients().values() {
+                prop_assert_eq!(client.total(), client.available() + client.held());
+                prop_assert!(client.held() >= Amount::ZERO, "negative held funds: {}", client);
+            }
+            if let (Some(before), Row::Deposit { .. } | Row::Withdrawal { .. }) = (&before, row) {
+                if before.locked() {
+                    let after = handler.clients().get(&row.client()).unwrap();
+                    prop_assert_eq!(after.available(), before.available());
+                }
+            }
+            if let (true, Row::Chargeback { tx, .. }) = (accepted, row) {
+                prop_assert!(charged_back.insert(tx), "transaction {} charged back twice", tx);
+            }
+            prop_assert!(handler.check_trial_balance().is_ok());
+        }
+    }
+
+    #[test]
+    fn the_handler_agrees_with_the_reference_model(rows in prop::collection::vec(row(), 1..64)) {
+        let mut handler = ClientTransactionHandler::new();
+        let mut model = Model::default();
+        for row in rows {
+            let accepted = handler.add_transaction(row.transaction()).is_ok();
+            prop_assert_eq!(accepted, model.apply(row), "{:?}", row);
+        }
+
+        for (id, expected) in &model.clients {
+            let client = handler.clients().get(id).unwrap();
+            prop_assert_eq!(client.available(), Amount::from_ten_thousandths(expected.available));
+            prop_assert_eq!(client.held(), Amount::from_ten_thousandths(expected.held));
+            prop_assert_eq!(client.locked(), expected.locked);
+        }
+    }
+}